@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::{copy_contents, errors, forward_slashes, lock, CopyJob, GeneralResult};
+
+
+/// Archive formats accepted as a source, picked by file extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+// Extensions for each kind, longest first so ".tar.gz" wins over ".gz".
+const EXTENSIONS: &[(&str, ArchiveKind)] = &[
+    (".tar.gz", ArchiveKind::TarGz),
+    (".tar.zst", ArchiveKind::TarZst),
+    (".tgz", ArchiveKind::TarGz),
+    (".tzst", ArchiveKind::TarZst),
+    (".tar", ArchiveKind::Tar),
+    (".zip", ArchiveKind::Zip),
+];
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let (_, kind) = split_extension(path)?;
+        Some(kind)
+    }
+}
+
+fn split_extension(path: &Path) -> Option<(String, ArchiveKind)> {
+    let file_name = path.file_name()?.to_str()?;
+    let lowercase_name = file_name.to_lowercase();
+    EXTENSIONS.iter()
+        .find(|(extension, _)| lowercase_name.ends_with(extension))
+        .map(|(extension, kind)| (file_name[..file_name.len() - extension.len()].to_owned(), *kind))
+}
+
+/// The archive's file name without its extension, e.g. "widgets" for
+/// "widgets.tar.gz". Used as the folder name with `--include-root`.
+pub fn root_name(path: &Path) -> Option<OsString> {
+    split_extension(path).map(|(name, _)| OsString::from(name))
+}
+
+/// Copies the contents of an archive into `to_dir` as if it were a folder,
+/// nothing is extracted anywhere else first.
+pub fn copy_archive(archive_path: &Path, kind: ArchiveKind, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    let file = fs::File::open(archive_path)?;
+    match kind {
+        ArchiveKind::Tar => copy_tar(tar::Archive::new(file), archive_path, to_dir, job),
+        ArchiveKind::TarGz => copy_tar(tar::Archive::new(GzDecoder::new(file)), archive_path, to_dir, job),
+        ArchiveKind::TarZst => copy_tar(tar::Archive::new(zstd::Decoder::new(file)?), archive_path, to_dir, job),
+        ArchiveKind::Zip => copy_zip(zip::ZipArchive::new(file)?, archive_path, to_dir, job),
+    }
+}
+
+fn copy_tar<R: Read>(mut archive: tar::Archive<R>, archive_path: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let relative_path = match enclosed_path(&entry_path) {
+            Some(relative_path) => relative_path,
+            None => {
+                println!("Moving on, path leaves the destination: {:?}", entry_path);
+                continue;
+            }
+        };
+
+        let entry_path = relative_path;
+        if job.excluded(&entry_path) {
+            continue;
+        }
+        let relative_path = job.render_path(&entry_path)?;
+        let entry_type = entry.header().entry_type();
+        if relative_path.as_os_str().is_empty() {
+            // The archive's own "./" entry
+            continue;
+        } else if entry_type.is_dir() {
+            job.create_dir(&to_dir.join(&relative_path))?;
+        } else if entry_type.is_file() {
+            copy_entry(&mut entry, archive_path, &entry_path, &relative_path, to_dir, job)?;
+        } else {
+            println!("Moving on, not a regular file: {:?}", relative_path);
+        }
+    }
+    Ok(())
+}
+
+fn copy_zip<R: Read + io::Seek>(mut archive: zip::ZipArchive<R>, archive_path: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let relative_path = match entry.enclosed_name().as_deref().and_then(enclosed_path) {
+            Some(relative_path) => relative_path,
+            None => {
+                println!("Moving on, path leaves the destination: {:?}", entry.name());
+                continue;
+            }
+        };
+
+        let entry_path = relative_path;
+        if job.excluded(&entry_path) {
+            continue;
+        }
+        let relative_path = job.render_path(&entry_path)?;
+        if relative_path.as_os_str().is_empty() {
+            continue;
+        } else if entry.is_dir() {
+            job.create_dir(&to_dir.join(&relative_path))?;
+        } else {
+            copy_entry(&mut entry, archive_path, &entry_path, &relative_path, to_dir, job)?;
+        }
+    }
+    Ok(())
+}
+
+// Copies a file entry, noting where it came from when the job keeps a lock.
+fn copy_entry(
+    entry: &mut dyn Read, archive_path: &Path, entry_path: &Path, relative_path: &Path, to_dir: &Path, job: &mut CopyJob
+) -> GeneralResult {
+    if job.lock.is_none() {
+        return copy_contents(entry, relative_path, to_dir, job)
+    }
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents)?;
+    job.origin = Some(lock::Origin::inner(archive_path, entry_path, &contents)?);
+    copy_contents(&mut contents.as_slice(), relative_path, to_dir, job)
+}
+
+/// Contents of the file at `inner_path` in an archive, None if it has no such file.
+pub fn read_entry(archive_path: &Path, inner_path: &Path) -> Result<Option<Vec<u8>>, errors::CupeyError> {
+    let kind = match ArchiveKind::from_path(archive_path) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+    let file = fs::File::open(archive_path)?;
+    match kind {
+        ArchiveKind::Tar => read_tar_entry(tar::Archive::new(file), inner_path),
+        ArchiveKind::TarGz => read_tar_entry(tar::Archive::new(GzDecoder::new(file)), inner_path),
+        ArchiveKind::TarZst => read_tar_entry(tar::Archive::new(zstd::Decoder::new(file)?), inner_path),
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                if !entry.is_dir() && entry.enclosed_name().as_deref().and_then(enclosed_path).as_deref() == Some(inner_path) {
+                    let mut contents = Vec::new();
+                    entry.read_to_end(&mut contents)?;
+                    return Ok(Some(contents))
+                }
+            }
+            Ok(None)
+        },
+    }
+}
+
+fn read_tar_entry<R: Read>(mut archive: tar::Archive<R>, inner_path: &Path) -> Result<Option<Vec<u8>>, errors::CupeyError> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() && enclosed_path(&entry.path()?).as_deref() == Some(inner_path) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            return Ok(Some(contents))
+        }
+    }
+    Ok(None)
+}
+
+// Entry path with "." parts dropped, None if it is absolute or climbs out with "..".
+fn enclosed_path(path: &Path) -> Option<PathBuf> {
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => enclosed.push(part),
+            Component::CurDir => {},
+            _ => return None,
+        }
+    }
+    Some(enclosed)
+}
+
+// Tar output, optionally compressed.
+enum TarOutput {
+    Plain(tar::Builder<fs::File>),
+    Gz(tar::Builder<GzEncoder<fs::File>>),
+    Zst(tar::Builder<zstd::Encoder<'static, fs::File>>),
+}
+
+enum Output {
+    Tar(TarOutput),
+    Zip(zip::ZipWriter<fs::File>),
+}
+
+/// Collects files into an archive instead of writing them to the destination.
+///
+/// Entries are named after their destination path relative to `root`, i.e.
+/// exactly where a normal run would have put them.
+pub struct ArchiveWriter {
+    output: Output,
+    output_path: PathBuf,
+    root: PathBuf,
+    /// Keep file permissions and modification times, otherwise entries get
+    /// fixed ones so the same files always give the same archive.
+    preserve_metadata: bool,
+    written: HashSet<PathBuf>,
+}
+
+impl std::fmt::Debug for ArchiveWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "ArchiveWriter({:?})", self.output_path)
+    }
+}
+
+impl ArchiveWriter {
+    /// Creates the archive at `output_path`, its format picked by extension.
+    pub fn create(output_path: &Path, root: &Path, preserve_metadata: bool) -> Result<Self, errors::CupeyError> {
+        let kind = ArchiveKind::from_path(output_path).ok_or_else(|| {
+            let message = format!("'{}' isn't a .tar, .tar.gz, .tar.zst or .zip file", output_path.to_str().unwrap());
+            errors::CupeyError::new(message, errors::ErrorKind::ArchiveError)
+        })?;
+
+        let file = fs::File::create(output_path)?;
+        let output = match kind {
+            ArchiveKind::Tar => Output::Tar(TarOutput::Plain(tar::Builder::new(file))),
+            ArchiveKind::TarGz => Output::Tar(TarOutput::Gz(
+                tar::Builder::new(GzEncoder::new(file, flate2::Compression::default()))
+            )),
+            ArchiveKind::TarZst => Output::Tar(TarOutput::Zst(tar::Builder::new(zstd::Encoder::new(file, 0)?))),
+            ArchiveKind::Zip => Output::Zip(zip::ZipWriter::new(file)),
+        };
+
+        Ok(ArchiveWriter {
+            output,
+            output_path: output_path.canonicalize()?,
+            root: root.to_owned(),
+            preserve_metadata,
+            written: HashSet::new(),
+        })
+    }
+
+    /// True for the archive being written, so a run never tries to pack it into itself.
+    pub fn is_output(&self, path: &Path) -> bool {
+        path.canonicalize().is_ok_and(|path| path == self.output_path)
+    }
+
+    pub fn add_dir(&mut self, destination_dir: &Path) -> GeneralResult {
+        let name = match self.entry_name(destination_dir) {
+            Some(name) => name,
+            // The root itself
+            None => return Ok(()),
+        };
+        if !self.written.insert(name.clone()) {
+            return Ok(())
+        }
+
+        match &mut self.output {
+            Output::Tar(tar_output) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                tar_append(tar_output, &mut header, &name, io::empty())?;
+            },
+            Output::Zip(zip_writer) => {
+                zip_writer.add_directory(forward_slashes(&name), zip::write::SimpleFileOptions::default())?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Adds a file from disk, false if something was already added under that name.
+    pub fn append_file(&mut self, orig_file_path: &Path, destination_file_path: &Path) -> Result<bool, errors::CupeyError> {
+        let meta = fs::metadata(orig_file_path)?;
+        let file = fs::File::open(orig_file_path)?;
+        self.append(file, meta.len(), Some(&meta), destination_file_path)
+    }
+
+    /// Adds a file from memory, false if something was already added under that name.
+    pub fn append_bytes(&mut self, contents: &[u8], destination_file_path: &Path) -> Result<bool, errors::CupeyError> {
+        self.append(contents, contents.len() as u64, None, destination_file_path)
+    }
+
+    fn append<R: Read>(&mut self, mut contents: R, len: u64, meta: Option<&fs::Metadata>, destination_file_path: &Path) -> Result<bool, errors::CupeyError> {
+        let name = self.entry_name(destination_file_path).unwrap();
+        if !self.written.insert(name.clone()) {
+            println!("Moving on, already in archive: {:?}", name);
+            return Ok(false)
+        }
+        let meta = meta.filter(|_| self.preserve_metadata);
+
+        match &mut self.output {
+            Output::Tar(tar_output) => {
+                let mut header = tar::Header::new_gnu();
+                match meta {
+                    Some(meta) => header.set_metadata_in_mode(meta, tar::HeaderMode::Complete),
+                    None => header.set_mode(0o644),
+                }
+                header.set_size(len);
+                tar_append(tar_output, &mut header, &name, contents)?;
+            },
+            Output::Zip(zip_writer) => {
+                let mut options = zip::write::SimpleFileOptions::default();
+                if let Some(meta) = meta {
+                    if let Some(mode) = unix_mode(meta) {
+                        options = options.unix_permissions(mode);
+                    }
+                    if let Some(modified) = zip_date_time(meta) {
+                        options = options.last_modified_time(modified);
+                    }
+                }
+                zip_writer.start_file(forward_slashes(&name), options)?;
+                io::copy(&mut contents, zip_writer)?;
+            },
+        }
+
+        println!("Added {:?} to archive", name);
+        Ok(true)
+    }
+
+    /// Writes out the end of the archive, nothing is usable until this is called.
+    pub fn finish(self) -> GeneralResult {
+        match self.output {
+            Output::Tar(TarOutput::Plain(builder)) => {
+                builder.into_inner()?.flush()?;
+            },
+            Output::Tar(TarOutput::Gz(builder)) => {
+                builder.into_inner()?.finish()?.flush()?;
+            },
+            Output::Tar(TarOutput::Zst(builder)) => {
+                builder.into_inner()?.finish()?.flush()?;
+            },
+            Output::Zip(zip_writer) => {
+                zip_writer.finish()?.flush()?;
+            },
+        }
+        println!("Wrote archive {:?}", self.output_path);
+        Ok(())
+    }
+
+    fn entry_name(&self, destination_path: &Path) -> Option<PathBuf> {
+        let name = destination_path.strip_prefix(&self.root).unwrap_or(destination_path);
+        if name.as_os_str().is_empty() {
+            return None
+        }
+        Some(name.to_owned())
+    }
+}
+
+fn tar_append<R: Read>(tar_output: &mut TarOutput, header: &mut tar::Header, name: &Path, contents: R) -> io::Result<()> {
+    match tar_output {
+        TarOutput::Plain(builder) => builder.append_data(header, name, contents),
+        TarOutput::Gz(builder) => builder.append_data(header, name, contents),
+        TarOutput::Zst(builder) => builder.append_data(header, name, contents),
+    }
+}
+
+#[cfg(unix)]
+fn unix_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Zip stores local date and time fields, UTC is used here.
+fn zip_date_time(meta: &fs::Metadata) -> Option<zip::DateTime> {
+    let secs = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let (year, month, day, hour, minute, second) = civil_date_time(secs);
+    zip::DateTime::from_date_and_time(year as u16, month as u8, day as u8, hour as u8, minute as u8, second as u8).ok()
+}
+
+// Seconds since 1970-01-01 to a UTC calendar date and time of day.
+pub(crate) fn civil_date_time(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year, month as u32, day as u32,
+        (secs_of_day / 3600) as u32, (secs_of_day % 3600 / 60) as u32, (secs_of_day % 60) as u32,
+    )
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+use crate::{errors, hooks, manifest};
+
+
+/// Flags and filters that can be set once instead of on every run.
+///
+/// Only options that combine with anything else given on the command line
+/// can be set, `--move`, `--link`, `--mirror` and the like can't.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub overwrite: Option<bool>,
+    pub include_root: Option<bool>,
+    pub preserve_metadata: Option<bool>,
+    pub dart_imports: Option<bool>,
+    pub no_input: Option<bool>,
+    pub no_lock: Option<bool>,
+    pub no_deps: Option<bool>,
+    pub skip_conflicts: Option<bool>,
+    /// Undo the copy when a hook fails.
+    pub rollback: Option<bool>,
+    /// Run no hooks at all, from the config or from copied folders' manifests.
+    pub no_hooks: Option<bool>,
+    /// Globs of source files never copied, relative to the folder copied from.
+    pub exclude: Vec<String>,
+    /// Run around every copy, before the ones of the copied folders' manifests.
+    pub hooks: hooks::Hooks,
+}
+
+impl Settings {
+    /// Whether the flag with clap name `name` is turned on.
+    pub fn flag(&self, name: &str) -> bool {
+        let value = match name {
+            "overwrite" => self.overwrite,
+            "include_root" => self.include_root,
+            "preserve_metadata" => self.preserve_metadata,
+            "dart_imports" => self.dart_imports,
+            "no_input" => self.no_input,
+            "no_lock" => self.no_lock,
+            "no_deps" => self.no_deps,
+            "skip_conflicts" => self.skip_conflicts,
+            "rollback" => self.rollback,
+            "no_hooks" => self.no_hooks,
+            _ => None,
+        };
+        value.unwrap_or(false)
+    }
+
+    /// `exclude` compiled, None when there is nothing to exclude.
+    pub fn exclude_set(&self) -> Result<Option<GlobSet>, errors::CupeyError> {
+        if self.exclude.is_empty() {
+            return Ok(None)
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.exclude {
+            let glob = Glob::new(pattern).map_err(|err| {
+                errors::CupeyError::new(format!("Bad exclude pattern: {}", err), errors::ErrorKind::ConfigError)
+            })?;
+            builder.add(glob);
+        }
+        let set = builder.build().map_err(|err| {
+            errors::CupeyError::new(err.to_string(), errors::ErrorKind::ConfigError)
+        })?;
+        Ok(Some(set))
+    }
+
+    // Values set in `other` win, excludes and hooks add up.
+    fn layer(&mut self, other: &Settings) {
+        let flags = [
+            (&mut self.overwrite, other.overwrite),
+            (&mut self.include_root, other.include_root),
+            (&mut self.preserve_metadata, other.preserve_metadata),
+            (&mut self.dart_imports, other.dart_imports),
+            (&mut self.no_input, other.no_input),
+            (&mut self.no_lock, other.no_lock),
+            (&mut self.no_deps, other.no_deps),
+            (&mut self.skip_conflicts, other.skip_conflicts),
+            (&mut self.rollback, other.rollback),
+            (&mut self.no_hooks, other.no_hooks),
+        ];
+        for (flag, value) in flags {
+            if value.is_some() {
+                *flag = value;
+            }
+        }
+        self.exclude.extend(other.exclude.iter().cloned());
+        self.hooks.extend(&other.hooks);
+    }
+}
+
+// One config file as written. The project's is also its manifest, other keys
+// belong to that.
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: BTreeMap<String, Settings>,
+}
+
+/// Settings from the user's config (see `user_config_path`) and the
+/// `[defaults]` and `[profiles.<name>]` tables of the current directory's
+/// `.cupey.toml`:
+///
+/// ```toml
+/// [defaults]
+/// overwrite = true
+/// exclude = ["*.g.dart"]
+///
+/// [defaults.hooks]
+/// post = ["dart format ."]
+///
+/// [profiles.flutter]
+/// dart_imports = true
+/// skip_conflicts = true
+/// exclude = ["test/**"]
+/// ```
+///
+/// From lowest to highest precedence: user defaults, project defaults, the
+/// user's profile, the project's profile, then flags given on the command line,
+/// which can turn settings off as well as on (`--no-overwrite`, `--lock`...).
+#[derive(Debug, Default)]
+pub struct Config {
+    user: ConfigFile,
+    project: ConfigFile,
+}
+
+impl Config {
+    /// Loads the user's config and `project_dir`'s, either may be missing.
+    pub fn load(project_dir: &Path) -> Result<Self, errors::CupeyError> {
+        Ok(Config {
+            user: read(&user_config_path())?,
+            project: read(&project_dir.join(manifest::MANIFEST_FILE_NAME))?,
+        })
+    }
+
+    /// The settings in effect, with `profile`'s on top.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings, errors::CupeyError> {
+        let mut settings = Settings::default();
+        settings.layer(&self.user.defaults);
+        settings.layer(&self.project.defaults);
+
+        if let Some(profile) = profile {
+            let layers: Vec<&Settings> = [&self.user, &self.project].iter()
+                .filter_map(|file| file.profiles.get(profile))
+                .collect();
+            if layers.is_empty() {
+                let known: BTreeSet<&str> = self.user.profiles.keys().chain(self.project.profiles.keys())
+                    .map(String::as_str)
+                    .collect();
+                let known: Vec<&str> = known.into_iter().collect();
+                let message = if known.is_empty() {
+                    format!("No profile named '{}', none are configured", profile)
+                } else {
+                    format!("No profile named '{}', configured profiles are: {}", profile, known.join(", "))
+                };
+                return Err(errors::CupeyError::new(message, errors::ErrorKind::ConfigError))
+            }
+            for layer in layers {
+                settings.layer(layer);
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// `cupey/config.toml` in the user's config folder: `$XDG_CONFIG_HOME`, else
+/// `~/.config`, or `%APPDATA%` on Windows. With `$CUPEY_HOME` set it is
+/// `$CUPEY_HOME/config.toml` instead, so everything cupey keeps moves together.
+pub fn user_config_path() -> PathBuf {
+    if let Some(home) = env::var_os("CUPEY_HOME") {
+        return PathBuf::from(home).join("config.toml")
+    }
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| if cfg!(windows) { env::var_os("APPDATA").map(PathBuf::from) } else { None })
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    match config_dir {
+        Some(config_dir) => config_dir.join("cupey").join("config.toml"),
+        None => crate::cupey_home().join("config.toml"),
+    }
+}
+
+fn read(path: &Path) -> Result<ConfigFile, errors::CupeyError> {
+    if !path.is_file() {
+        return Ok(ConfigFile::default())
+    }
+    toml::from_str(&fs::read_to_string(path)?).map_err(|err| {
+        let message = format!("Couldn't read '{}': {}", path.to_str().unwrap(), err);
+        errors::CupeyError::new(message, errors::ErrorKind::ConfigError)
+    })
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use regex::{Captures, Regex};
+
+use crate::{errors, forward_slashes, link};
+
+
+/// A Dart package: the folder holding its `pubspec.yaml` and the name declared there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub root: PathBuf,
+}
+
+impl Package {
+    /// The package `path` belongs to, found by looking for a `pubspec.yaml`
+    /// in its folder and every folder above.
+    pub fn find(path: &Path) -> Option<Self> {
+        path.ancestors()
+            .map(|dir| dir.join("pubspec.yaml"))
+            .filter(|pubspec| pubspec.is_file())
+            .find_map(|pubspec| {
+                let name = package_name(&fs::read_to_string(&pubspec).ok()?)?;
+                Some(Package { name, root: pubspec.parent()?.to_owned() })
+            })
+    }
+
+    pub fn lib_dir(&self) -> PathBuf {
+        self.root.join("lib")
+    }
+}
+
+/// The top level `name:` of a pubspec.
+pub fn package_name(pubspec: &str) -> Option<String> {
+    pubspec.lines()
+        .find_map(|line| line.strip_prefix("name:"))
+        .map(|name| name.split('#').next().unwrap_or("").trim().trim_matches(|c| c == '\'' || c == '"').to_owned())
+        .filter(|name| !name.is_empty())
+}
+
+/// Rewrites `import`, `export` and `part` lines of copied `.dart` files so
+/// they keep pointing at the same files after the copy.
+///
+/// Every Dart file copied is recorded along with where it went. Once copying
+/// is done, imports of the source package (`package:widget_lib/...`) and
+/// relative imports that lead to another copied file are pointed at its copy,
+/// using the destination project's package name when both files end up in
+/// its `lib` folder and a relative path otherwise. Other packages and `dart:`
+/// imports are left alone.
+#[derive(Debug, Default)]
+pub struct DartImports {
+    // Source file -> destination file, both absolute.
+    copies: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl DartImports {
+    /// Notes where a source file is copied to, files that aren't Dart files are ignored.
+    pub fn record(&mut self, source: &Path, destination: &Path) -> std::io::Result<()> {
+        if source.extension().is_some_and(|extension| extension == "dart") {
+            self.copies.insert(source.canonicalize()?, absolute(destination)?);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the imports of every recorded file among `copied`, using the
+    /// package `project_dir` belongs to. Returns the files changed.
+    pub fn rewrite(&self, project_dir: &Path, copied: &[PathBuf]) -> Result<Vec<PathBuf>, errors::CupeyError> {
+        let project = Package::find(&absolute(project_dir)?);
+        // Skipped files keep their contents
+        let written = copied.iter().map(|path| absolute(path)).collect::<std::io::Result<Vec<PathBuf>>>()?;
+
+        let import_regex = Regex::new(r#"(?m)^(\s*(?:import|export|part(?:\s+of)?)\s+)(['"])([^'"]+)(['"])"#).unwrap();
+        let mut rewritten = Vec::new();
+
+        for (source, destination) in &self.copies {
+            if !written.contains(destination) {
+                continue;
+            }
+            let source_package = Package::find(source);
+            let contents = fs::read_to_string(destination)?;
+            let mut changed = 0;
+
+            let updated = import_regex.replace_all(&contents, |captures: &Captures| {
+                let uri = &captures[3];
+                let new_uri = self.rewrite_uri(uri, source, destination, source_package.as_ref(), project.as_ref());
+                match new_uri {
+                    Some(new_uri) if new_uri != uri => {
+                        changed += 1;
+                        format!("{}{}{}{}", &captures[1], &captures[2], new_uri, &captures[4])
+                    },
+                    _ => captures[0].to_owned(),
+                }
+            });
+
+            if changed > 0 {
+                fs::write(destination, updated.as_bytes())?;
+                println!("Rewrote {} import(s) in {:?}", changed, destination);
+                rewritten.push(destination.to_owned());
+            }
+        }
+        Ok(rewritten)
+    }
+
+    // None when the import doesn't point at a copied file.
+    fn rewrite_uri(
+        &self, uri: &str, source: &Path, destination: &Path, source_package: Option<&Package>, project: Option<&Package>
+    ) -> Option<String> {
+        let is_package = uri.starts_with("package:");
+        let target = if is_package {
+            let package = source_package?;
+            let inner = uri.strip_prefix(&format!("package:{}/", package.name))?;
+            package.lib_dir().join(inner)
+        } else if uri.contains(':') {
+            // dart:, http: and the like
+            return None
+        } else {
+            normalize(&source.parent()?.join(uri))
+        };
+
+        let target_copy = match self.copies.get(&target) {
+            Some(target_copy) => target_copy,
+            None => {
+                if is_package || !destination.parent()?.join(uri).exists() {
+                    println!("Moving on, {} imports {}, which wasn't copied", destination.display(), uri);
+                }
+                return None
+            },
+        };
+
+        if let Some(project) = project {
+            let lib_dir = project.lib_dir();
+            if is_package && destination.starts_with(&lib_dir) && target_copy.starts_with(&lib_dir) {
+                let inner = target_copy.strip_prefix(&lib_dir).ok()?;
+                return Some(format!("package:{}/{}", project.name, forward_slashes(inner)))
+            }
+        }
+        Some(forward_slashes(&link::relative_path(destination.parent()?, target_copy)))
+    }
+}
+
+fn absolute(path: &Path) -> std::io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(normalize(path))
+    } else {
+        Ok(normalize(&std::env::current_dir()?.join(path)))
+    }
+}
+
+// Resolves "." and ".." without touching the filesystem, the file may not exist.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{errors, forward_slashes, lock, manifest, template, transform, CopyJob};
+
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// How a file compares between the source and the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    /// Only in the source, a copy would add it.
+    Added,
+    /// In both with different contents.
+    Changed,
+    Identical,
+    /// Only in the destination, a copy leaves it alone.
+    DestinationOnly,
+}
+
+impl Change {
+    fn label(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Identical => "identical",
+            Change::DestinationOnly => "only here",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Change::Added => GREEN,
+            Change::Changed => YELLOW,
+            Change::Identical => "",
+            Change::DestinationOnly => RED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDiff {
+    /// Relative to the destination, placeholders filled in.
+    pub relative_path: PathBuf,
+    /// None for `Change::DestinationOnly`.
+    pub source: Option<PathBuf>,
+    pub destination: PathBuf,
+    pub change: Change,
+    /// What a copy would write, when the template or transforms change the
+    /// source's contents on the way.
+    pub rendered: Option<Vec<u8>>,
+}
+
+impl FileDiff {
+    /// The file's status, colored when `color` is true.
+    pub fn status_line(&self, color: bool) -> String {
+        let label = format!("{:9}", self.change.label());
+        let path = self.relative_path.display();
+        if color && !self.change.color().is_empty() {
+            format!("{}{}{}  {}", self.change.color(), label, RESET, path)
+        } else {
+            format!("{}  {}", label, path)
+        }
+    }
+
+    /// Unified diff from the destination's contents to the source's, None
+    /// unless the file changed and both sides are text.
+    pub fn unified_diff(&self, color: bool) -> Result<Option<String>, errors::CupeyError> {
+        let (old, new) = match self.texts()? {
+            Some(texts) => texts,
+            None => return Ok(None),
+        };
+        let name = forward_slashes(&self.relative_path);
+        let patch = diffy::DiffOptions::new()
+            .set_original_filename(format!("a/{}", name))
+            .set_modified_filename(format!("b/{}", name))
+            .create_patch(&old, &new);
+        let formatter = if color { diffy::PatchFormatter::new().with_color() } else { diffy::PatchFormatter::new() };
+        let text = formatter.fmt_patch(&patch).to_string();
+        Ok(Some(text))
+    }
+
+    /// Lines the source adds and removes, None unless the file changed and
+    /// both sides are text.
+    pub fn line_counts(&self) -> Result<Option<(usize, usize)>, errors::CupeyError> {
+        let (old, new) = match self.texts()? {
+            Some(texts) => texts,
+            None => return Ok(None),
+        };
+        let patch = diffy::create_patch(&old, &new);
+        let (mut insertions, mut deletions) = (0, 0);
+        for line in patch.hunks().iter().flat_map(|hunk| hunk.lines()) {
+            match line {
+                diffy::Line::Insert(_) => insertions += 1,
+                diffy::Line::Delete(_) => deletions += 1,
+                diffy::Line::Context(_) => {},
+            }
+        }
+        Ok(Some((insertions, deletions)))
+    }
+
+    // Destination and source contents of a changed text file.
+    fn texts(&self) -> Result<Option<(String, String)>, errors::CupeyError> {
+        let source = match (&self.source, self.change) {
+            (Some(source), Change::Changed) => source,
+            _ => return Ok(None),
+        };
+        let new = match &self.rendered {
+            Some(rendered) => rendered.clone(),
+            None => fs::read(source)?,
+        };
+        let old = fs::read(&self.destination)?;
+        if template::is_binary(&old) || template::is_binary(&new) {
+            return Ok(None)
+        }
+        match (String::from_utf8(old), String::from_utf8(new)) {
+            (Ok(old), Ok(new)) => Ok(Some((old, new))),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Compares `source`, a folder or a single file, with what is at `to_dir`,
+/// walking both the way a copy with `job` would. Names and contents go
+/// through the job's template and transforms first. Files the source's
+/// manifest or the job excludes are left out, as is cupey's own lock.
+///
+/// Files only the destination has are listed from the folders the source
+/// has too, and from the top of `to_dir` only when it is the source's own
+/// copy (`include_root`), so the rest of a project isn't listed.
+pub fn compare(source: &Path, to_dir: &Path, job: &mut CopyJob) -> Result<Vec<FileDiff>, errors::CupeyError> {
+    let mut diffs = Vec::new();
+    if source.is_file() {
+        // file_name is Some for a file
+        let relative_path = PathBuf::from(source.file_name().unwrap());
+        let mut comparison = Comparison { job, diffs: &mut diffs };
+        comparison.compare_file(source, to_dir, &relative_path, Path::new(""))?;
+        return Ok(diffs)
+    }
+
+    job.manifest = manifest::Manifest::load(source)?;
+    let mut comparison = Comparison { job, diffs: &mut diffs };
+    let walked = comparison.walk(source, to_dir);
+    job.manifest = None;
+    walked?;
+    diffs.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    Ok(diffs)
+}
+
+struct Comparison<'c> {
+    job: &'c CopyJob,
+    diffs: &'c mut Vec<FileDiff>,
+}
+
+impl Comparison<'_> {
+    fn skipped(&self, relative_path: &Path) -> bool {
+        relative_path == Path::new(lock::LOCK_FILE_NAME) || self.job.excluded(relative_path)
+    }
+
+    fn walk(&mut self, source: &Path, to_dir: &Path) -> Result<(), errors::CupeyError> {
+        self.walk_source(source, to_dir, Path::new(""), Path::new(""))?;
+        if to_dir.is_dir() {
+            self.walk_destination(Some(source), to_dir, Path::new(""), Path::new(""))?;
+        }
+        Ok(())
+    }
+
+    // relative_dir - dir relative to the source, rendered_dir - to_dir relative to the destination
+    fn walk_source(
+        &mut self, dir: &Path, to_dir: &Path, relative_dir: &Path, rendered_dir: &Path
+    ) -> Result<(), errors::CupeyError> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let relative_path = relative_dir.join(entry.file_name());
+            if self.skipped(&relative_path) {
+                continue;
+            }
+            if entry.path().is_dir() {
+                let name = self.job.render_name(&entry.file_name(), &relative_path)?;
+                self.walk_source(&entry.path(), &to_dir.join(&name), &relative_path, &rendered_dir.join(&name))?;
+            } else {
+                self.compare_file(&entry.path(), to_dir, &relative_path, rendered_dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    // dir - the source folder to_dir is a copy of, None when the source has no such folder
+    fn walk_destination(
+        &mut self, dir: Option<&Path>, to_dir: &Path, relative_dir: &Path, rendered_dir: &Path
+    ) -> Result<(), errors::CupeyError> {
+        // Source entries by the name their copies get
+        let mut copies = BTreeMap::new();
+        if let Some(dir) = dir {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let relative_path = relative_dir.join(entry.file_name());
+                if !self.skipped(&relative_path) {
+                    copies.insert(self.job.render_name(&entry.file_name(), &relative_path)?, (entry.path(), relative_path));
+                }
+            }
+        }
+        // Whatever else sits next to the copy at the top belongs to the project
+        let top_level = rendered_dir.as_os_str().is_empty();
+        let listed = !top_level || self.job.include_root;
+
+        for entry in fs::read_dir(to_dir)? {
+            let entry = entry?;
+            let rendered_path = rendered_dir.join(entry.file_name());
+            match (copies.get(&entry.file_name()), entry.path().is_dir()) {
+                (Some((source, relative_path)), true) if source.is_dir() => {
+                    self.walk_destination(Some(source), &entry.path(), relative_path, &rendered_path)?;
+                },
+                // Compared with its source already
+                (Some(_), _) => {},
+                _ if !listed || self.skipped(&rendered_path) => {},
+                (None, true) => self.walk_destination(None, &entry.path(), &rendered_path, &rendered_path)?,
+                (None, false) => self.diffs.push(FileDiff {
+                    relative_path: rendered_path, source: None, destination: entry.path(), change: Change::DestinationOnly,
+                    rendered: None,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    // Compares the source file at `relative_path` with its copy in `to_dir`.
+    fn compare_file(
+        &mut self, source: &Path, to_dir: &Path, relative_path: &Path, rendered_dir: &Path
+    ) -> Result<(), errors::CupeyError> {
+        // file_name is Some for a file
+        let name = self.job.render_name(source.file_name().unwrap(), relative_path)?;
+        let (destination, rendered_path) = (to_dir.join(&name), rendered_dir.join(&name));
+        let transforms = self.job.transforms_for(relative_path);
+        let rendered = match transforms.is_empty() {
+            true => None,
+            false => Some(transform::apply(&transforms, &rendered_path, &mut fs::File::open(source)?)?),
+        };
+
+        let change = if !destination.is_file() {
+            Change::Added
+        } else if rendered.clone().map_or_else(|| fs::read(source), Ok)? == fs::read(&destination)? {
+            Change::Identical
+        } else {
+            Change::Changed
+        };
+        self.diffs.push(FileDiff {
+            relative_path: rendered_path, source: Some(source.to_owned()), destination, change, rendered
+        });
+        Ok(())
+    }
+}
+
+/// git style `--stat` lines, one per changed file plus a summary.
+pub fn stat(diffs: &[FileDiff], color: bool) -> Result<String, errors::CupeyError> {
+    let changed: Vec<&FileDiff> = diffs.iter().filter(|diff| diff.change == Change::Changed).collect();
+    let width = changed.iter().map(|diff| forward_slashes(&diff.relative_path).len()).max().unwrap_or(0);
+    let (mut total_insertions, mut total_deletions) = (0, 0);
+    let mut text = String::new();
+
+    for diff in &changed {
+        let name = forward_slashes(&diff.relative_path);
+        match diff.line_counts()? {
+            Some((insertions, deletions)) => {
+                total_insertions += insertions;
+                total_deletions += deletions;
+                let (plus, minus) = ("+".repeat(insertions.min(40)), "-".repeat(deletions.min(40)));
+                let bar = if color {
+                    format!("{}{}{}{}{}{}", GREEN, plus, RESET, RED, minus, RESET)
+                } else {
+                    format!("{}{}", plus, minus)
+                };
+                // Writing to a String can't fail
+                writeln!(text, " {:width$} | {:4} {}", name, insertions + deletions, bar, width = width).unwrap();
+            },
+            None => writeln!(text, " {:width$} | Bin", name, width = width).unwrap(),
+        }
+    }
+
+    let count = |change: Change| diffs.iter().filter(|diff| diff.change == change).count();
+    writeln!(
+        text, " {} file(s) changed, {} insertion(s)(+), {} deletion(s)(-), {} added, {} only here, {} identical",
+        changed.len(), total_insertions, total_deletions, count(Change::Added), count(Change::DestinationOnly),
+        count(Change::Identical)
+    ).unwrap();
+    Ok(text)
+}
@@ -2,16 +2,26 @@ use std::fmt;
 use std::process;
 use std::io::Write;
 
-use clap;
 
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ErrorKind {
     OsError,
     ClapError,
     DirIsFile,
     DirEmpty,
-    IoError
+    IoError,
+    Unsupported,
+    VerifyFailed,
+    NoRootName,
+    ArchiveError,
+    GitError,
+    TemplateError,
+    ManifestError,
+    RegistryError,
+    LockError,
+    ConfigError,
+    HookError,
+    TransformError
 }
 
 impl fmt::Display for ErrorKind {
@@ -21,7 +31,19 @@ impl fmt::Display for ErrorKind {
             ErrorKind::IoError => write!(f, "IO Error"),
             ErrorKind::DirEmpty => write!(f, "DirEmpty"),
             ErrorKind::DirIsFile => write!(f, "DirIsFile"),
-            ErrorKind::OsError => write!(f, "OS Error")
+            ErrorKind::OsError => write!(f, "OS Error"),
+            ErrorKind::Unsupported => write!(f, "Unsupported"),
+            ErrorKind::VerifyFailed => write!(f, "VerifyFailed"),
+            ErrorKind::NoRootName => write!(f, "NoRootName"),
+            ErrorKind::ArchiveError => write!(f, "Archive Error"),
+            ErrorKind::GitError => write!(f, "Git Error"),
+            ErrorKind::TemplateError => write!(f, "Template Error"),
+            ErrorKind::ManifestError => write!(f, "Manifest Error"),
+            ErrorKind::RegistryError => write!(f, "Registry Error"),
+            ErrorKind::LockError => write!(f, "Lock Error"),
+            ErrorKind::ConfigError => write!(f, "Config Error"),
+            ErrorKind::HookError => write!(f, "Hook Error"),
+            ErrorKind::TransformError => write!(f, "Transform Error")
         }
     }
 }
@@ -36,13 +58,17 @@ pub struct CupeyError {
 
 impl CupeyError {
     pub fn new(message: String, error_kind: ErrorKind) -> Self {
-        CupeyError { message: message, error_kind: error_kind}
+        CupeyError { message, error_kind }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.error_kind
     }
 
     pub fn exit(&self) {
         // Write to stdout before exiting
         let out = std::io::stdout();
-        writeln!(&mut out.lock(), "{}", self.to_string()).expect("Failed to write to stdout");
+        writeln!(&mut out.lock(), "{}", self).expect("Failed to write to stdout");
         process::exit(0)
     }
 }
@@ -53,7 +79,7 @@ impl fmt::Display for CupeyError {
         write!(
             f,
             "Error Type: {}\nError Message: {}",
-            self.message, self.error_kind.to_string()
+            self.message, self.error_kind
         )
     }
 }
@@ -78,3 +104,21 @@ impl From<clap::Error> for CupeyError {
         }
     }
 }
+
+impl From<zip::result::ZipError> for CupeyError {
+    fn from(err: zip::result::ZipError) -> Self {
+        CupeyError {
+            message: err.to_string(),
+            error_kind: ErrorKind::ArchiveError
+        }
+    }
+}
+
+impl From<git2::Error> for CupeyError {
+    fn from(err: git2::Error) -> Self {
+        CupeyError {
+            message: err.message().to_owned(),
+            error_kind: ErrorKind::GitError
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use git2::{ObjectType, Repository, Tree};
+
+use crate::{copy_contents, errors, lock, CopyJob, GeneralResult};
+
+
+// Tree entry file modes
+const MODE_SYMLINK: i32 = 0o120000;
+
+/// A folder inside a local git repository as it was at some revision.
+///
+/// Files are read straight from the object database, nothing is checked out.
+#[derive(Debug, Clone)]
+pub struct GitSource {
+    pub repo_path: PathBuf,
+    /// Anything `git rev-parse` understands: a branch, tag, commit, "HEAD~2"...
+    pub rev: String,
+    /// Folder inside the repository to copy, the whole tree if None.
+    pub subdir: Option<PathBuf>,
+}
+
+impl GitSource {
+    /// Folder name used with `--include-root`: the subdir's name, or the repository's.
+    pub fn root_name(&self) -> Option<OsString> {
+        match &self.subdir {
+            Some(subdir) => subdir.file_name().map(OsString::from),
+            None => self.repo_path.canonicalize().ok()?.file_name().map(OsString::from),
+        }
+    }
+
+    /// Copies the tree's contents into `to_dir`.
+    pub fn copy(&self, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+        let repo = Repository::open(&self.repo_path)?;
+        let object = repo.revparse_single(&self.rev)?;
+        let mut tree = object.peel_to_tree()?;
+        if let Some(lock) = job.lock.as_mut() {
+            lock.source_info = lock::SourceInfo {
+                library: None,
+                rev: Some(self.rev.clone()),
+                revision: object.peel_to_commit().ok().map(|commit| commit.id().to_string()),
+            };
+        }
+
+        if let Some(subdir) = &self.subdir {
+            let entry = tree.get_path(subdir)?;
+            if entry.kind() != Some(ObjectType::Tree) {
+                let message = format!("'{}' is not a folder at {}", subdir.to_str().unwrap(), self.rev);
+                return Err(errors::CupeyError::new(message, errors::ErrorKind::DirIsFile))
+            }
+            tree = repo.find_tree(entry.id())?;
+        }
+
+        job.create_dir(to_dir)?;
+        let tree_dir = self.subdir.clone().unwrap_or_default();
+        self.copy_tree(&repo, &tree, to_dir, Path::new(""), &tree_dir, job)
+    }
+
+    // tree_dir - path of the tree inside the repository, for the lock
+    fn copy_tree(
+        &self, repo: &Repository, tree: &Tree, to_dir: &Path, relative_dir: &Path, tree_dir: &Path, job: &mut CopyJob
+    ) -> GeneralResult {
+        for entry in tree.iter() {
+            let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+            // Excludes are relative to the folder copied from
+            let source_path = tree_dir.join(&name);
+            let subdir = self.subdir.as_deref().unwrap_or_else(|| Path::new(""));
+            if job.excluded(source_path.strip_prefix(subdir).unwrap_or(&source_path)) {
+                continue;
+            }
+            let relative_path = relative_dir.join(job.render_name(name.as_ref(), &relative_dir.join(&name))?);
+
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    job.create_dir(&to_dir.join(&relative_path))?;
+                    self.copy_tree(repo, &repo.find_tree(entry.id())?, to_dir, &relative_path, &tree_dir.join(&name), job)?;
+                },
+                Some(ObjectType::Blob) if entry.filemode() == MODE_SYMLINK => {
+                    println!("Moving on, not a regular file: {:?}", relative_path);
+                },
+                Some(ObjectType::Blob) => {
+                    let blob = repo.find_blob(entry.id())?;
+                    if job.lock.is_some() {
+                        job.origin = Some(lock::Origin::inner(&self.repo_path, &tree_dir.join(&name), blob.content())?);
+                    }
+                    copy_contents(&mut blob.content(), &relative_path, to_dir, job)?;
+                },
+                // Submodules
+                _ => println!("Moving on, not a regular file: {:?}", relative_path),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Contents of the file at `path` in the repository as of `rev`, None if
+/// there is no such file.
+pub fn read_file(repo_path: &Path, rev: &str, path: &Path) -> Result<Option<Vec<u8>>, errors::CupeyError> {
+    let repo = Repository::open(repo_path)?;
+    let tree = repo.revparse_single(rev)?.peel_to_tree()?;
+    let entry = match tree.get_path(path) {
+        Ok(entry) if entry.kind() == Some(ObjectType::Blob) => entry,
+        _ => return Ok(None),
+    };
+    let blob = repo.find_blob(entry.id())?;
+    let contents = blob.content().to_owned();
+    Ok(Some(contents))
+}
+
+/// Commit checked out in the repository `path` is in, None outside a repository.
+pub fn head_revision(path: &Path) -> Option<String> {
+    let repo = Repository::discover(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
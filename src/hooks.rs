@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+use crate::{errors, link, template, GeneralResult};
+
+
+/// Shell commands run around a copy, from the `[hooks]` table of a manifest
+/// or of the config:
+///
+/// ```toml
+/// [hooks]
+/// pre = ["git diff --quiet"]
+/// post = ["dart format ."]
+/// per_file = ["chmod 644 \"$CUPEY_PATH\""]
+/// ```
+///
+/// Commands run through the shell in the destination directory. `pre` hooks
+/// run before anything is copied, with the sources in `CUPEY_SOURCES`. `post`
+/// hooks run once everything is copied, with the files written in
+/// `CUPEY_PATHS`. `per_file` hooks run after each file is written, with it in
+/// `CUPEY_PATH`. Paths are also passed on stdin, one per line.
+///
+/// A copied folder's hooks only run once the user agrees to them, see `confirm`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre: Vec<String>,
+    pub post: Vec<String>,
+    pub per_file: Vec<String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre.is_empty() && self.post.is_empty() && self.per_file.is_empty()
+    }
+
+    /// Adds `other`'s hooks after ours.
+    pub fn extend(&mut self, other: &Hooks) {
+        self.pre.extend(other.pre.iter().cloned());
+        self.post.extend(other.post.iter().cloned());
+        self.per_file.extend(other.per_file.iter().cloned());
+    }
+
+    /// Fills in placeholders, commands from a template's manifest can use its variables.
+    pub fn render(mut self, template: &template::Template) -> Self {
+        for commands in [&mut self.pre, &mut self.post, &mut self.per_file] {
+            for command in commands.iter_mut() {
+                if let Ok(rendered) = template.render(command) {
+                    *command = rendered;
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Lists the commands `source`'s manifest wants to run and asks the user to
+/// confirm, they come from whoever wrote the folder.
+pub fn confirm(source: &Path, hooks: &Hooks) -> io::Result<bool> {
+    println!("The manifest of {:?} wants to run these commands:", source);
+    for (when, commands) in [("pre", &hooks.pre), ("post", &hooks.post), ("per file", &hooks.per_file)] {
+        for command in commands {
+            println!("  {}: {}", when, command);
+        }
+    }
+    crate::ask_yes_no("Run them?")
+}
+
+/// Runs `command` in `dir` with `env` set and `paths` on its stdin. A command
+/// that can't be started or exits unsuccessfully is a `HookError`.
+pub fn run(command: &str, dir: &Path, env: &[(&str, String)], paths: &[PathBuf]) -> GeneralResult {
+    println!("Running hook: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell.arg(command)
+        .current_dir(dir)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| hook_error(command, &err.to_string()))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let lines: String = paths.iter().map(|path| format!("{}\n", path.display())).collect();
+        // A hook that doesn't read its input closes the pipe early, that's fine
+        match stdin.write_all(lines.as_bytes()) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+            _ => {},
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(hook_error(command, &status.to_string()))
+    }
+    Ok(())
+}
+
+/// Newline separated, for the `CUPEY_*` variables.
+pub fn path_list(paths: &[PathBuf]) -> String {
+    let lines: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+    lines.join("\n")
+}
+
+fn hook_error(command: &str, reason: &str) -> errors::CupeyError {
+    let message = format!("Hook '{}' failed: {}", command, reason);
+    errors::CupeyError::new(message, errors::ErrorKind::HookError)
+}
+
+// Tells apart the journals of one process.
+static JOURNAL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// What a run changed in the destination, so a failed hook can undo it.
+///
+/// Files about to be replaced and files and folders about to be deleted are
+/// copied aside first, files and folders about to be created are noted. The copies are deleted when the journal is
+/// dropped.
+#[derive(Debug)]
+pub struct Journal {
+    backup_dir: PathBuf,
+    // Destination and where its old contents were copied, None if it didn't exist
+    files: Vec<(PathBuf, Option<PathBuf>)>,
+    seen: HashSet<PathBuf>,
+    created_dirs: Vec<PathBuf>,
+}
+
+impl Journal {
+    pub fn new() -> io::Result<Self> {
+        let count = JOURNAL_COUNT.fetch_add(1, Ordering::Relaxed);
+        let backup_dir = std::env::temp_dir().join(format!("cupey-rollback-{}-{}", std::process::id(), count));
+        fs::create_dir_all(&backup_dir)?;
+        Ok(Journal { backup_dir, files: Vec::new(), seen: HashSet::new(), created_dirs: Vec::new() })
+    }
+
+    /// Call before `path` is written, only the first call for a path counts.
+    pub fn before_write(&mut self, path: &Path) -> io::Result<()> {
+        if !self.seen.insert(path.to_owned()) {
+            return Ok(())
+        }
+        let backup = if path.is_file() {
+            let backup = self.backup_dir.join(self.files.len().to_string());
+            fs::copy(path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        self.files.push((path.to_owned(), backup));
+        Ok(())
+    }
+
+    /// Call before `path`, a file or a folder, is deleted.
+    pub fn before_remove(&mut self, path: &Path) -> io::Result<()> {
+        if !self.seen.insert(path.to_owned()) {
+            return Ok(())
+        }
+        let backup = self.backup_dir.join(self.files.len().to_string());
+        copy_tree(path, &backup)?;
+        self.files.push((path.to_owned(), Some(backup)));
+        Ok(())
+    }
+
+    /// Call before `dir` is created along with any missing parents.
+    pub fn before_create_dir(&mut self, dir: &Path) {
+        let mut missing: Vec<PathBuf> = dir.ancestors()
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+            .map(Path::to_owned)
+            .collect();
+        // Outermost first, they are removed in reverse
+        missing.reverse();
+        self.created_dirs.extend(missing);
+    }
+
+    /// Puts back what was replaced and removes what was created.
+    pub fn rollback(self) -> io::Result<()> {
+        for (path, backup) in self.files.iter().rev() {
+            match backup {
+                Some(backup) => copy_tree(backup, path)?,
+                None if path.is_file() => fs::remove_file(path)?,
+                None => {},
+            }
+        }
+        for dir in self.created_dirs.iter().rev() {
+            // Folders something else put files in since stay
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+// Copies a file, or a folder with everything in it, links as links.
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if metadata.file_type().is_symlink() {
+        link::symlink_file(&fs::read_link(from)?, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.backup_dir);
+    }
+}
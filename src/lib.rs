@@ -1,13 +1,38 @@
-use std::ffi::OsString;
+#![allow(non_snake_case)]
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::env;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::time::Duration;
 
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, SubCommand};
 
 mod errors;
 mod cupey_traits;
+pub mod archive;
+pub mod config;
+pub mod dart;
+pub mod diff;
+pub mod git;
+pub mod hooks;
+pub mod link;
+pub mod lock;
+pub mod manifest;
+pub mod mirror;
+pub mod pubspec;
+pub mod registry;
+pub mod search;
+pub mod status;
+pub mod report;
+pub mod sync;
+pub mod template;
+pub mod transform;
+pub mod update;
+#[cfg(target_os = "linux")]
+pub mod watch;
 
 type GeneralResult = Result<(), errors::CupeyError>;
 
@@ -20,9 +45,15 @@ pub struct Cupey<'a> {
     // roll_back: Option<String>,
 }
 
+impl<'a> Default for Cupey<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> Cupey<'a> {
     pub fn new() -> Self {
-        Self::new_from(env::args_os().into_iter()).unwrap_or_else(|e| e.exit())
+        Self::new_from(env::args_os()).unwrap_or_else(|e| e.exit())
     }
 
     pub fn new_from<I, T>(args: I) -> Result<Self, clap::Error> 
@@ -35,60 +66,1312 @@ impl<'a> Cupey<'a> {
         let app = App::new("cupey")
             .version("0.0.1")
             .about("Recursively copy files from one folder to another")
-            .author("Chidi Nnadi");
+            .author("Chidi Nnadi")
+            .setting(AppSettings::SubcommandsNegateReqs);
+
+        let app = app.arg(
+            from_arg()
+                .help("The folder or file to copy from, can be given more than once")
+                .required_unless("from_git")
+                .multiple(true)
+                .number_of_values(1)
+        );
+
+        let overwrite_flag = Arg::with_name("overwrite")
+            .help(
+                    "Overrite all existing files the destination directory that share same name with file being copied."
+                )
+            .long("overwrite")
+            .short("o");
+
+        let app = app.arg(overwrite_flag);
+
+        let mirror_flag = Arg::with_name("mirror")
+            .help(
+                    "After copying, delete files and folders in the destination directory that don't exist in the source."
+                )
+            .long("mirror");
+
+        let app = app.arg(mirror_flag);
+
+        let dry_run_flag = Arg::with_name("dry_run")
+            .help("With --mirror, list what would be deleted without copying or deleting anything.")
+            .long("dry-run")
+            .requires("mirror");
+
+        let app = app.arg(dry_run_flag);
+
+        let yes_flag = Arg::with_name("yes")
+            .help("Don't ask for confirmation before deleting files or running hooks from copied folders' manifests.")
+            .long("yes")
+            .short("y");
+
+        let app = app.arg(yes_flag);
+
+        let incremental_flag = Arg::with_name("incremental")
+            .help(
+                    "Only copy files that are new or changed since the last run, files edited in the destination since then are left alone unless --overwrite is given."
+                )
+            .long("incremental");
+
+        let app = app.arg(incremental_flag);
 
-        let from_arg = Arg::with_name("from_arg")
-            .help("The folder location to copy from")
-            .short("f")
-            .long("from")
+        let move_flag = Arg::with_name("move")
+            .help(
+                    "Move files instead of copying them, folders left empty in the source are deleted."
+                )
+            .long("move")
+            .conflicts_with_all(&["mirror", "incremental"]);
+
+        let app = app.arg(move_flag);
+
+        let link_arg = Arg::with_name("link")
+            .help(
+                    "Recreate the folders but fill them with links to the source files instead of copies."
+                )
+            .long("link")
+            .value_name("KIND")
+            .takes_value(true)
+            .possible_values(&["hard", "symlink", "relative-symlink"])
+            .conflicts_with_all(&["move", "incremental"]);
+
+        let app = app.arg(link_arg);
+
+        let include_root_flag = Arg::with_name("include_root")
+            .help(
+                    "Copy the source folder itself into the destination directory, not just its contents."
+                )
+            .long("include-root");
+
+        let app = app.arg(include_root_flag);
+
+        let to_archive_arg = Arg::with_name("to_archive")
+            .help(
+                    "Write everything into this archive (.tar, .tar.gz, .tar.zst or .zip) instead of the destination directory."
+                )
+            .long("to-archive")
             .value_name("PATH")
             .takes_value(true)
             .validator(|value| {
-                let path = Path::new(&value);
-                if path.exists() {
-                    if path.is_dir() {
-                        return Ok(());
-                    }
-                    return Err("Sorry this path isn't a directory".to_owned());
+                match archive::ArchiveKind::from_path(Path::new(&value)) {
+                    Some(_) => Ok(()),
+                    None => Err("Sorry the archive should end in .tar, .tar.gz, .tar.zst or .zip".to_owned()),
+                }
+            })
+            .conflicts_with_all(&["move", "link", "incremental", "mirror"]);
+
+        let app = app.arg(to_archive_arg);
+
+        let preserve_metadata_flag = Arg::with_name("preserve_metadata")
+            .help("Keep file permissions and modification times in the archive.")
+            .long("preserve-metadata")
+            .requires("to_archive");
+
+        let app = app.arg(preserve_metadata_flag);
+
+        let from_git_arg = Arg::with_name("from_git")
+            .help("A local git repository to copy from, files are read at --rev without checking anything out.")
+            .long("from-git")
+            .value_name("REPO")
+            .takes_value(true)
+            .validator(|value| {
+                if Path::new(&value).is_dir() {
+                    Ok(())
                 } else {
-                    return Err("Sorry this path doesnt't exist".to_owned());
+                    Err("Sorry this repository path doesnt't exist".to_owned())
                 }
-            });
+            })
+            .conflicts_with_all(&["move", "link", "incremental", "mirror"]);
 
-        let app = app.arg(from_arg);
+        let app = app.arg(from_git_arg);
 
-        let overwrite_flag = Arg::with_name("overwrite")
+        let rev_arg = Arg::with_name("rev")
+            .help("Branch, tag or commit to copy from with --from-git, HEAD by default.")
+            .long("rev")
+            .value_name("REF")
+            .takes_value(true)
+            .requires("from_git");
+
+        let app = app.arg(rev_arg);
+
+        let subdir_arg = Arg::with_name("subdir")
+            .help("Folder inside the repository to copy with --from-git, the whole repository by default.")
+            .long("subdir")
+            .value_name("PATH")
+            .takes_value(true)
+            .requires("from_git");
+
+        let app = app.arg(subdir_arg);
+
+        let var_arg = Arg::with_name("var")
             .help(
-                    "Overrite all existing files the destination directory that share same name with file being copied."
+                    "Substitute VALUE for {{KEY}} in copied file names, folder names and text files, can be given more than once."
                 )
-            .long("overwrite")
-            .short("o");
+            .long("var")
+            .value_name("KEY=VALUE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|value| template::Template::parse_var(&value).map(|_| ()))
+            .conflicts_with_all(&["move", "link", "incremental", "mirror"]);
 
-        let app = app.arg(overwrite_flag);
+        let app = app.arg(var_arg);
+
+        let no_input_flag = Arg::with_name("no_input")
+            .help(
+                    "Never ask for template variables, use defaults and fail when a variable has none."
+                )
+            .long("no-input");
+
+        let app = app.arg(no_input_flag);
+
+        let dart_imports_flag = Arg::with_name("dart_imports")
+            .help(
+                    "Point imports in copied .dart files at their copies, using the destination project's package name from its pubspec.yaml."
+                )
+            .long("dart-imports")
+            .conflicts_with_all(&["move", "link", "incremental", "to_archive", "from_git"]);
+
+        let app = app.arg(dart_imports_flag);
+
+        let no_lock_flag = Arg::with_name("no_lock")
+            .help(
+                    "Don't record where copied files came from in the destination's cupey.lock."
+                )
+            .long("no-lock");
+
+        let app = app.arg(no_lock_flag).args(&config_args());
+
+        let rollback_flag = Arg::with_name("rollback")
+            .help(
+                    "Put back the files a run copied or replaced when one of its hooks fails."
+                )
+            .long("rollback");
+
+        let app = app.arg(rollback_flag);
+
+        let no_hooks_flag = Arg::with_name("no_hooks")
+            .help(
+                    "Don't run any hooks, neither the config's nor those of copied folders' manifests."
+                )
+            .long("no-hooks");
+
+        let app = app.arg(no_hooks_flag).args(&negation_args(&[
+            "overwrite", "include_root", "preserve_metadata", "dart_imports", "no_input", "no_lock", "rollback", "no_hooks"
+        ]));
+
+        let watch_subcommand = SubCommand::with_name("watch")
+            .about("Copy files, then keep copying them over whenever they change in the source folder")
+            .arg(from_arg().required(true))
+            .arg(
+                Arg::with_name("overwrite")
+                    .help("Overwrite existing files during the initial copy.")
+                    .long("overwrite")
+                    .short("o")
+            )
+            .arg(
+                Arg::with_name("mirror")
+                    .help("Delete files from the destination directory when they are deleted from the source.")
+                    .long("mirror")
+            )
+            .arg(
+                Arg::with_name("debounce")
+                    .help("Milliseconds to wait for changes to settle before copying.")
+                    .long("debounce")
+                    .value_name("MILLISECONDS")
+                    .takes_value(true)
+                    .default_value("200")
+                    .validator(|value| {
+                        value.parse::<u64>()
+                            .map(|_| ())
+                            .map_err(|_| "Sorry debounce should be a number of milliseconds".to_owned())
+                    })
+            );
+
+        let app = app.subcommand(watch_subcommand);
+
+        let add_subcommand = SubCommand::with_name("add")
+            .about("Register a folder under a short name for cupey use")
+            .arg(
+                Arg::with_name("name")
+                    .help("Name to register the folder as")
+                    .required(true)
+                    .validator(|value| {
+                        if registry::is_valid_name(&value) {
+                            Ok(())
+                        } else {
+                            Err("Sorry names can only hold letters, numbers, '_', '-' and '.'".to_owned())
+                        }
+                    })
+            )
+            .arg(from_arg().help("The folder to register").long("").required(true).index(2))
+            .arg(
+                Arg::with_name("description")
+                    .help("Shown by cupey list, the folder's manifest description by default.")
+                    .long("description")
+                    .short("d")
+                    .value_name("TEXT")
+                    .takes_value(true)
+            );
+
+        let app = app.subcommand(add_subcommand);
+
+        let list_subcommand = SubCommand::with_name("list")
+            .about("Show the registered folders");
+
+        let app = app.subcommand(list_subcommand);
+
+        let use_subcommand = SubCommand::with_name("use")
+            .about("Copy from a registered folder into the current directory")
+            .arg(
+                Arg::with_name("name")
+                    .help("Name the folder was registered as, add /<path> to only copy that file or folder out of it")
+                    .required(true)
+            )
+            .arg(
+                Arg::with_name("overwrite")
+                    .help("Overwrite existing files that share a name with files being copied.")
+                    .long("overwrite")
+                    .short("o")
+            )
+            .arg(
+                Arg::with_name("include_root")
+                    .help("Copy the folder itself, not just its contents.")
+                    .long("include-root")
+            )
+            .arg(
+                Arg::with_name("var")
+                    .help("Substitute VALUE for {{KEY}} in copied names and text files, can be given more than once.")
+                    .long("var")
+                    .value_name("KEY=VALUE")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| template::Template::parse_var(&value).map(|_| ()))
+            )
+            .arg(
+                Arg::with_name("no_input")
+                    .help("Never ask for template variables.")
+                    .long("no-input")
+            )
+            .arg(
+                Arg::with_name("dart_imports")
+                    .help("Point imports in copied .dart files at their copies.")
+                    .long("dart-imports")
+            )
+            .arg(
+                Arg::with_name("no_deps")
+                    .help("Don't copy the registered folders listed in the manifest's dependencies.")
+                    .long("no-deps")
+            )
+            .arg(
+                Arg::with_name("no_lock")
+                    .help("Don't record where copied files came from in cupey.lock.")
+                    .long("no-lock")
+            )
+            .arg(
+                Arg::with_name("rollback")
+                    .help("Put back the files copied or replaced when a hook fails.")
+                    .long("rollback")
+            )
+            .arg(
+                Arg::with_name("no_hooks")
+                    .help("Don't run any hooks.")
+                    .long("no-hooks")
+            )
+            .arg(
+                Arg::with_name("yes")
+                    .help("Run hooks from the folders' manifests without asking.")
+                    .long("yes")
+                    .short("y")
+            )
+            .args(&config_args())
+            .args(&negation_args(&[
+                "overwrite", "include_root", "no_input", "dart_imports", "no_deps", "no_lock", "rollback", "no_hooks"
+            ]));
+
+        let app = app.subcommand(use_subcommand);
+
+        let search_subcommand = SubCommand::with_name("search")
+            .about("Search the registered folders by name, description and optionally contents")
+            .arg(
+                Arg::with_name("query")
+                    .help("Words to look for, all of them have to match")
+                    .required(true)
+                    .multiple(true)
+            )
+            .arg(
+                Arg::with_name("contents")
+                    .help("Search inside text files too.")
+                    .long("contents")
+                    .short("c")
+            )
+            .arg(
+                Arg::with_name("limit")
+                    .help("Show at most this many matches.")
+                    .long("limit")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                    .default_value("20")
+                    .validator(|value| {
+                        value.parse::<usize>()
+                            .map(|_| ())
+                            .map_err(|_| "Sorry limit should be a number".to_owned())
+                    })
+            );
+
+        let app = app.subcommand(search_subcommand);
+
+        let status_subcommand = SubCommand::with_name("status")
+            .about("Show which copied files changed in their source, were edited here, or both");
+
+        let app = app.subcommand(status_subcommand);
+
+        let update_subcommand = SubCommand::with_name("update")
+            .about("Bring copied files up to date with their sources, merging in upstream changes")
+            .arg(
+                Arg::with_name("paths")
+                    .help("Only update these files or folders, all copied files by default")
+                    .multiple(true)
+            )
+            .arg(
+                Arg::with_name("skip_conflicts")
+                    .help("Leave files alone when both sides changed the same lines, instead of writing conflict markers.")
+                    .long("skip-conflicts")
+            )
+            .args(&config_args())
+            .args(&negation_args(&["skip_conflicts"]));
+
+        let app = app.subcommand(update_subcommand);
+
+        let diff_subcommand = SubCommand::with_name("diff")
+            .about("Show what copying would change in the current directory, without copying")
+            .arg(from_arg().required(true).multiple(true).number_of_values(1))
+            .arg(
+                Arg::with_name("include_root")
+                    .help("Compare against the folder's own copy, like --include-root when copying.")
+                    .long("include-root")
+            )
+            .arg(
+                Arg::with_name("var")
+                    .help("Substitute VALUE for {{KEY}} in names and text files like a copy would, can be given more than once.")
+                    .long("var")
+                    .value_name("KEY=VALUE")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| template::Template::parse_var(&value).map(|_| ()))
+            )
+            .arg(
+                Arg::with_name("no_input")
+                    .help("Never ask for template variables.")
+                    .long("no-input")
+            )
+            .arg(
+                Arg::with_name("stat")
+                    .help("Only show how many lines each changed file would gain and lose.")
+                    .long("stat")
+            )
+            .arg(
+                Arg::with_name("color")
+                    .help("When to color the output.")
+                    .long("color")
+                    .value_name("WHEN")
+                    .takes_value(true)
+                    .possible_values(&["auto", "always", "never"])
+                    .default_value("auto")
+            )
+            .args(&config_args())
+            .args(&negation_args(&["include_root", "no_input"]));
+
+        let app = app.subcommand(diff_subcommand);
 
         let matches = app.get_matches_from_safe(args)?;
 
-        return Ok(Cupey {
+        Ok(Cupey {
             current_dir, matches
-        });
+        })
     }
     
+    /// Runs whichever subcommand was given, or a plain copy when there is none.
+    pub fn run(&self) -> GeneralResult {
+        match self.matches.subcommand() {
+            ("watch", Some(watch_matches)) => self.watch(watch_matches),
+            ("add", Some(add_matches)) => self.add(add_matches),
+            ("list", Some(_)) => self.list(),
+            ("use", Some(use_matches)) => self.use_source(use_matches),
+            ("search", Some(search_matches)) => self.search(search_matches),
+            ("status", Some(_)) => self.status(),
+            ("update", Some(update_matches)) => self.update(update_matches),
+            ("diff", Some(diff_matches)) => self.diff(diff_matches),
+            _ => self.copy_files(),
+        }
+    }
+
     pub fn copy_files(&self) -> GeneralResult {
         // from_arg has already been validated, safe to unwrap
-        let originating_dir = self.matches.value_of("from_arg").unwrap();
-        let overwrite = self.matches.is_present("overwrite");
-        if overwrite {
-            visit_dirs(&Path::new(originating_dir), &self.current_dir, true)?;    
+        let sources: Vec<&Path> = self.matches.values_of("from_arg")
+            .map(|values| values.map(Path::new).collect())
+            .unwrap_or_default();
+        self.copy_from(&self.matches, &sources)
+    }
+
+    // Copies `sources` with the copy options found in `matches`, which can be
+    // a subcommand's when it takes a subset of them.
+    fn copy_from(&self, matches: &clap::ArgMatches, sources: &[&Path]) -> GeneralResult {
+        let settings = self.settings(matches)?;
+        let overwrite = flag_set(matches, &settings, "overwrite");
+        let mut job = CopyJob::new(overwrite);
+        job.move_files = matches.is_present("move");
+        // link has already been validated, safe to unwrap
+        job.link = matches.value_of("link").map(|kind| kind.parse().unwrap());
+        job.include_root = flag_set(matches, &settings, "include_root");
+        job.exclude = settings.exclude_set()?;
+        // vars have already been validated, safe to unwrap
+        let vars: BTreeMap<String, String> = matches.values_of("var")
+            .map(|vars| vars.map(|var| template::Template::parse_var(var).unwrap()).collect())
+            .unwrap_or_default();
+        if !vars.is_empty() {
+            job.template = Some(template::Template::new(vars.clone()));
+        }
+        // Imports are worked out from where the source files sit on disk, archives have no such place
+        let archive_source = sources.iter().find(|source| source.is_file() && archive::ArchiveKind::from_path(source).is_some());
+        if let (true, Some(source)) = (matches.is_present("dart_imports"), archive_source) {
+            let message = format!("'{}' is an archive, --dart-imports only works with folders and files", source.to_str().unwrap());
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+        }
+        // The config's dart_imports gives way to the flags --dart-imports conflicts with
+        let dart_imports_allowed = !["move", "link", "incremental", "to_archive", "from_git"].iter().any(|flag| matches.is_present(flag))
+            && archive_source.is_none();
+        if matches.is_present("dart_imports") || (flag_set(matches, &settings, "dart_imports") && dart_imports_allowed) {
+            job.dart_imports = Some(dart::DartImports::default());
+        }
+        if let Some(archive_path) = matches.value_of("to_archive") {
+            let preserve_metadata = flag_set(matches, &settings, "preserve_metadata");
+            job.archive_writer = Some(archive::ArchiveWriter::create(Path::new(archive_path), &self.current_dir, preserve_metadata)?);
+        } else if !flag_set(matches, &settings, "no_lock") {
+            job.lock = Some(lock::LockFile::load(&self.current_dir)?);
+        }
+        if flag_set(matches, &settings, "rollback") && job.archive_writer.is_none() {
+            if job.move_files || job.link.is_some() {
+                println!("Moving on, files moved or linked can't be rolled back if a hook fails");
+            } else {
+                job.journal = Some(hooks::Journal::new()?);
+            }
+        }
+        job.hook_dir = self.current_dir.clone();
+
+        let result = self.copy_with(matches, &settings, sources, &vars, &mut job);
+        if let (Err(err), Some(journal)) = (&result, job.journal.take()) {
+            if err.kind() == errors::ErrorKind::HookError {
+                journal.rollback()?;
+                // Incremental runs shouldn't remember copies that were undone
+                job.pending_sync_states.clear();
+                println!("A hook failed, the files copied were put back the way they were");
+            }
+        }
+        for mut state in job.pending_sync_states.drain(..) {
+            state.save()?;
+        }
+        result
+    }
+
+    // copy_from once the job is set up.
+    fn copy_with(
+        &self, matches: &clap::ArgMatches, settings: &config::Settings, sources: &[&Path],
+        vars: &BTreeMap<String, String>, job: &mut CopyJob
+    ) -> GeneralResult {
+        // Only names libraries in the lock, a broken registry shouldn't stop a copy
+        let registry = job.lock.as_ref().and_then(|_| registry::Registry::load().ok());
+
+        if matches.is_present("mirror") {
+            if sources.iter().any(|source| archive::ArchiveKind::from_path(source).is_some()) {
+                let message = "Archive sources can't be mirrored".to_owned();
+                return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+            }
+            if matches.is_present("dry_run") {
+                return self.mirror_dry_run(matches, settings, sources, vars, job)
+            }
+        }
+
+        // Sources sharing a destination are mirrored together, so one source
+        // doesn't delete what another just copied.
+        let mut destinations: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let mut messages = Vec::new();
+        let mut requirements = pubspec::PubspecRequirements::default();
+        let no_hooks = flag_set(matches, settings, "no_hooks");
+        let config_hooks = if no_hooks { hooks::Hooks::default() } else { settings.hooks.clone() };
+        let mut post_hooks = config_hooks.post.clone();
+
+        if !config_hooks.pre.is_empty() {
+            let source_paths: Vec<PathBuf> = sources.iter().map(|source| source.to_path_buf()).collect();
+            job.create_dir(&self.current_dir)?;
+            self.run_hooks(&config_hooks.pre, "CUPEY_SOURCES", &source_paths)?;
+        }
+
+        for &source in sources {
+            job.manifest = manifest::Manifest::load(source)?;
+            job.per_file_hooks = config_hooks.per_file.clone();
+            let mut pre_hooks = Vec::new();
+            if let Some(manifest) = &job.manifest {
+                if !manifest.variables.is_empty() {
+                    job.template = Some(template::Template::new(self.manifest_vars(matches, settings, source, manifest, vars)?));
+                }
+                let manifest_hooks = match &job.template {
+                    Some(template) => manifest.hooks.clone().render(template),
+                    None => manifest.hooks.clone(),
+                };
+                let manifest_hooks = if no_hooks || !self.hooks_allowed(matches, settings, source, &manifest_hooks)? {
+                    hooks::Hooks::default()
+                } else {
+                    manifest_hooks
+                };
+                pre_hooks = manifest_hooks.pre;
+                post_hooks.extend(manifest_hooks.post);
+                job.per_file_hooks.extend(manifest_hooks.per_file);
+                requirements.extend(match &job.template {
+                    Some(template) => manifest.pubspec.clone().render(template),
+                    None => manifest.pubspec.clone(),
+                });
+                for message in &manifest.messages {
+                    messages.push(match &job.template {
+                        Some(template) => template.render(message).unwrap_or_else(|_| message.to_owned()),
+                        None => message.to_owned(),
+                    });
+                }
+            }
+            if !pre_hooks.is_empty() {
+                job.create_dir(&self.current_dir)?;
+                self.run_hooks(&pre_hooks, "CUPEY_SOURCES", &[source.to_owned()])?;
+            }
+
+            if let Some(lock) = job.lock.as_mut() {
+                lock.source_info = lock::SourceInfo {
+                    library: registry.as_ref().and_then(|registry| registry.library_of(source)).map(str::to_owned),
+                    rev: None,
+                    revision: git::head_revision(source),
+                };
+            }
+
+            // Where the copied files land, what incremental runs and mirror work against.
+            let destination = job.destination(source, &self.current_dir)?;
+            if matches.is_present("incremental") {
+                job.sync_state = Some(sync::SyncState::load(source, &destination)?);
+            }
+
+            copy_source(source, &self.current_dir, job)?;
+
+            if let Some(state) = job.sync_state.take() {
+                job.pending_sync_states.push(state);
+            }
+            destinations.entry(destination).or_default().push(source.to_owned());
+
+            // The next source may have a manifest of its own, or none
+            if job.manifest.take().is_some_and(|manifest| !manifest.variables.is_empty()) {
+                job.template = if vars.is_empty() { None } else { Some(template::Template::new(vars.clone())) };
+            }
+        }
+
+        // Before the hooks run, so a failing one can put back what was deleted
+        if matches.is_present("mirror") {
+            for (destination, sources) in &destinations {
+                let removed = self.mirror(sources, destination, job.journal.as_mut())?;
+                job.report.removed.extend(removed);
+            }
+        }
+
+        if let Some(repo_path) = matches.value_of("from_git") {
+            job.per_file_hooks = config_hooks.per_file.clone();
+            let git_source = git::GitSource {
+                repo_path: PathBuf::from(repo_path),
+                rev: matches.value_of("rev").unwrap_or("HEAD").to_owned(),
+                subdir: matches.value_of("subdir").map(PathBuf::from),
+            };
+            let destination = match git_source.root_name() {
+                Some(root_name) if job.include_root => self.current_dir.join(root_name),
+                _ => self.current_dir.clone(),
+            };
+            git_source.copy(&destination, job)?;
+        }
+
+        if let Some(dart_imports) = &job.dart_imports {
+            let rewritten = dart_imports.rewrite(&self.current_dir, &job.report.copied)?;
+            if let Some(lock) = job.lock.as_mut() {
+                lock.rehash(&rewritten)?;
+            }
+        }
+        // An archive isn't a project, there is nothing to merge into
+        if !requirements.is_empty() && job.archive_writer.is_none() {
+            self.merge_pubspec(&requirements, job.journal.as_mut())?;
+        }
+
+        if !post_hooks.is_empty() {
+            self.run_hooks(&post_hooks, "CUPEY_PATHS", &job.report.copied)?;
+            // What hooks do to copied files, like formatting them, is part of the copy
+            if let Some(lock) = job.lock.as_mut() {
+                lock.rehash(&job.report.copied)?;
+            }
+        }
+
+        if let Some(writer) = job.archive_writer.take() {
+            writer.finish()?;
+        }
+        if let Some(mut lock) = job.lock.take() {
+            lock.save()?;
+        }
+
+        println!("Done: {}", job.report);
+        for message in messages {
+            println!("{}", message);
+        }
+        Ok(())
+    }
+
+    // Adds what the copied sources need to the destination project's pubspec.yaml.
+    fn merge_pubspec(&self, requirements: &pubspec::PubspecRequirements, journal: Option<&mut hooks::Journal>) -> GeneralResult {
+        let package = match dart::Package::find(&self.current_dir) {
+            Some(package) => package,
+            None => {
+                println!("No pubspec.yaml found, the copied files need:");
+                for line in requirements.merge_into("").added {
+                    println!("  {}", line);
+                }
+                return Ok(())
+            },
+        };
+        let pubspec_path = package.root.join("pubspec.yaml");
+        let outcome = requirements.merge_into(&fs::read_to_string(&pubspec_path)?);
+
+        if !outcome.added.is_empty() {
+            if let Some(journal) = journal {
+                journal.before_write(&pubspec_path)?;
+            }
+            fs::write(&pubspec_path, &outcome.pubspec)?;
+            for line in &outcome.added {
+                println!("Added to pubspec.yaml: {}", line);
+            }
+        }
+        for conflict in &outcome.conflicts {
+            println!("Version conflict, left alone: {}", conflict);
+        }
+        Ok(())
+    }
+
+    // Whether the hooks of `source`'s manifest may run. They come from whoever
+    // wrote the folder, so the user is asked first unless they said --yes.
+    fn hooks_allowed(
+        &self, matches: &clap::ArgMatches, settings: &config::Settings, source: &Path, hooks: &hooks::Hooks
+    ) -> Result<bool, errors::CupeyError> {
+        if hooks.is_empty() || matches.is_present("yes") {
+            return Ok(true)
+        }
+        let allowed = if !flag_set(matches, settings, "no_input") && io::stdin().is_terminal() {
+            hooks::confirm(source, hooks)?
+        } else {
+            false
+        };
+        if !allowed {
+            println!("Moving on, not running the hooks of {:?}'s manifest, pass --yes to run them", source);
+        }
+        Ok(allowed)
+    }
+
+    // Runs hook commands in the destination, `paths` go in the `key` variable and on stdin.
+    fn run_hooks(&self, commands: &[String], key: &str, paths: &[PathBuf]) -> GeneralResult {
+        let env = [
+            ("CUPEY_DESTINATION", self.current_dir.display().to_string()),
+            (key, hooks::path_list(paths)),
+        ];
+        for command in commands {
+            hooks::run(command, &self.current_dir, &env, paths)?;
+        }
+        Ok(())
+    }
+
+    // The config in effect for a run, none with --no-config.
+    fn settings(&self, matches: &clap::ArgMatches) -> Result<config::Settings, errors::CupeyError> {
+        if matches.is_present("no_config") {
+            return Ok(config::Settings::default())
+        }
+        config::Config::load(&self.current_dir)?.settings(matches.value_of("profile"))
+    }
+
+    // Variables for a source whose manifest declares some, asking for missing
+    // ones unless there is nobody to ask.
+    fn manifest_vars(
+        &self, matches: &clap::ArgMatches, settings: &config::Settings, source: &Path, manifest: &manifest::Manifest,
+        vars: &BTreeMap<String, String>
+    ) -> Result<BTreeMap<String, String>, errors::CupeyError> {
+        if ["move", "link", "incremental", "mirror"].iter().any(|flag| matches.is_present(flag)) {
+            let message = format!(
+                "'{}' is a template, it can't be copied with --move, --link, --incremental or --mirror",
+                source.to_str().unwrap()
+            );
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+        }
+
+        let stdin = io::stdin();
+        if !flag_set(matches, settings, "no_input") && stdin.is_terminal() {
+            manifest.resolve_vars(vars, Some(&mut stdin.lock()))
         } else {
-            visit_dirs(&Path::new(originating_dir), &self.current_dir, false)?;
+            manifest.resolve_vars(vars, None)
+        }
+    }
+
+    fn add(&self, add_matches: &clap::ArgMatches) -> GeneralResult {
+        // name and from_arg are required, safe to unwrap
+        let name = add_matches.value_of("name").unwrap();
+        let source = Path::new(add_matches.value_of("from_arg").unwrap());
+        if !source.is_dir() {
+            let message = format!("'{}' should be a folder to register", source.to_str().unwrap());
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::DirIsFile))
+        }
+
+        let mut registry = registry::Registry::load()?;
+        let replaced = registry.entries.contains_key(name);
+        registry.add(name, source, add_matches.value_of("description").map(str::to_owned))?;
+        registry.save()?;
+        if replaced {
+            println!("Updated {} -> {:?}", name, registry.entries[name].path);
+        } else {
+            println!("Added {} -> {:?}", name, registry.entries[name].path);
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> GeneralResult {
+        let registry = registry::Registry::load()?;
+        if registry.entries.is_empty() {
+            println!("Nothing registered yet, add a folder with cupey add <name> <path>");
+            return Ok(())
+        }
+
+        let width = registry.entries.keys().map(String::len).max().unwrap_or(0);
+        for (name, entry) in &registry.entries {
+            println!("{:width$}  {}", name, entry.path.display(), width = width);
+            if let Some(description) = &entry.description {
+                println!("{:width$}  {}", "", description, width = width);
+            }
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> GeneralResult {
+        let lock = lock::LockFile::load(&self.current_dir)?;
+        if lock.entries.is_empty() {
+            println!("Nothing recorded in {:?}, copy something here first", lock.path());
+            return Ok(())
+        }
+
+        let statuses = status::status(&lock)?;
+        let mut counts: BTreeMap<status::Drift, usize> = BTreeMap::new();
+        for file_status in &statuses {
+            *counts.entry(file_status.drift).or_default() += 1;
+            let library = file_status.entry.library.as_ref()
+                .map(|library| format!("  ({})", library))
+                .unwrap_or_default();
+            println!("{:16}  {}{}", file_status.drift, file_status.entry.path, library);
         }
 
+        let summary: Vec<String> = counts.iter().map(|(drift, count)| format!("{} {}", count, drift)).collect();
+        println!("Status: {}", summary.join(", "));
         Ok(())
     }
+
+    fn update(&self, update_matches: &clap::ArgMatches) -> GeneralResult {
+        let mut lock = lock::LockFile::load(&self.current_dir)?;
+        if lock.entries.is_empty() {
+            println!("Nothing recorded in {:?}, copy something here first", lock.path());
+            return Ok(())
+        }
+        // Same form as lock entries, forward slashes relative to the current directory
+        let paths: Vec<String> = update_matches.values_of("paths")
+            .map(|paths| paths.map(|path| path.replace('\\', "/").trim_start_matches("./").to_owned()).collect())
+            .unwrap_or_default();
+
+        let skip_conflicts = flag_set(update_matches, &self.settings(update_matches)?, "skip_conflicts");
+        let outcomes = update::update(&mut lock, &paths, !skip_conflicts)?;
+        lock.save()?;
+
+        let mut conflicts = 0;
+        for (path, outcome) in outcomes.iter().filter(|(_, outcome)| *outcome != update::UpdateOutcome::UpToDate) {
+            println!("{}: {}", path, outcome);
+            if matches!(outcome, update::UpdateOutcome::Conflicted | update::UpdateOutcome::ConflictSkipped) {
+                conflicts += 1;
+            }
+        }
+        let up_to_date = outcomes.iter().filter(|(_, outcome)| *outcome == update::UpdateOutcome::UpToDate).count();
+        println!("Done: {} file(s) checked, {} already up to date", outcomes.len(), up_to_date);
+        if conflicts > 0 {
+            println!("{} file(s) have conflicting changes, look for <<<<<<< markers or merge them by hand", conflicts);
+        }
+        Ok(())
+    }
+
+    fn diff(&self, diff_matches: &clap::ArgMatches) -> GeneralResult {
+        let color = match diff_matches.value_of("color") {
+            Some("always") => true,
+            Some("never") => false,
+            _ => io::stdout().is_terminal(),
+        };
+        let settings = self.settings(diff_matches)?;
+        let mut job = CopyJob {
+            include_root: flag_set(diff_matches, &settings, "include_root"),
+            exclude: settings.exclude_set()?,
+            ..CopyJob::default()
+        };
+
+        // vars have already been validated, safe to unwrap
+        let vars: BTreeMap<String, String> = diff_matches.values_of("var")
+            .map(|vars| vars.map(|var| template::Template::parse_var(var).unwrap()).collect())
+            .unwrap_or_default();
+
+        let mut diffs = Vec::new();
+        // from_arg is required, safe to unwrap
+        for source in diff_matches.values_of("from_arg").unwrap().map(Path::new) {
+            if source.is_file() && archive::ArchiveKind::from_path(source).is_some() {
+                let message = format!("'{}' is an archive, only folders and files can be compared", source.to_str().unwrap());
+                return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+            }
+            // Compared with what a copy would write, placeholders filled in
+            job.template = if vars.is_empty() { None } else { Some(template::Template::new(vars.clone())) };
+            if let Some(manifest) = manifest::Manifest::load(source)?.filter(|manifest| !manifest.variables.is_empty()) {
+                job.template = Some(template::Template::new(self.manifest_vars(diff_matches, &settings, source, &manifest, &vars)?));
+            }
+            let destination = job.destination(source, &self.current_dir)?;
+            diffs.extend(diff::compare(source, &destination, &mut job)?);
+        }
+
+        if diff_matches.is_present("stat") {
+            print!("{}", diff::stat(&diffs, color)?);
+            return Ok(())
+        }
+        for file_diff in &diffs {
+            println!("{}", file_diff.status_line(color));
+        }
+        for file_diff in &diffs {
+            if let Some(patch) = file_diff.unified_diff(color)? {
+                print!("\n{}", patch);
+            }
+        }
+        Ok(())
+    }
+
+    fn use_source(&self, use_matches: &clap::ArgMatches) -> GeneralResult {
+        // name is required, safe to unwrap
+        let name_and_path = use_matches.value_of("name").unwrap();
+        let registry = registry::Registry::load()?;
+        let source = registry.resolve(name_and_path)?;
+
+        // Dependencies are copied first, in the same run so there is one report
+        let mut sources = Vec::new();
+        if !flag_set(use_matches, &self.settings(use_matches)?, "no_deps") {
+            // The entry asked for always comes last
+            let dependencies = registry.with_dependencies(name_and_path)?;
+            for dependency in &dependencies[..dependencies.len() - 1] {
+                println!("Using dependency {}", dependency);
+                sources.push(registry.resolve(dependency)?);
+            }
+        }
+        sources.push(source);
+
+        let sources: Vec<&Path> = sources.iter().map(PathBuf::as_path).collect();
+        self.copy_from(use_matches, &sources)
+    }
+
+    fn search(&self, search_matches: &clap::ArgMatches) -> GeneralResult {
+        // query is required and limit has already been validated, safe to unwrap
+        let query: Vec<&str> = search_matches.values_of("query").unwrap().collect();
+        let limit: usize = search_matches.value_of("limit").unwrap().parse().unwrap();
+        let registry = registry::Registry::load()?;
+        let hits = search::search(&registry, &query.join(" "), search_matches.is_present("contents"))?;
+
+        if hits.is_empty() {
+            println!("Nothing matches '{}'", query.join(" "));
+            return Ok(())
+        }
+        for hit in hits.iter().take(limit) {
+            match &hit.kind {
+                search::HitKind::Source => {
+                    let description = registry.entries[&hit.source].description.as_deref().unwrap_or("");
+                    println!("{}  {}", hit.use_path(), description);
+                },
+                search::HitKind::Name => println!("{}", hit.use_path()),
+                search::HitKind::Contents { line_number, line } => {
+                    println!("{}:{}  {}", hit.use_path(), line_number, line);
+                },
+            }
+        }
+        if hits.len() > limit {
+            println!("... and {} more, raise --limit to see them", hits.len() - limit);
+        }
+        println!("Copy a match with cupey use <match>");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self, watch_matches: &clap::ArgMatches) -> GeneralResult {
+        // from_arg and debounce have already been validated, safe to unwrap
+        let originating_dir = Path::new(watch_matches.value_of("from_arg").unwrap());
+        if !originating_dir.is_dir() {
+            let message = format!("'{}' should be a folder to watch", originating_dir.to_str().unwrap());
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::DirIsFile))
+        }
+        let options = watch::WatchOptions {
+            mirror: watch_matches.is_present("mirror"),
+            debounce: Duration::from_millis(watch_matches.value_of("debounce").unwrap().parse().unwrap()),
+        };
+
+        // Watch before the initial copy so changes made during it aren't missed.
+        let mut watcher = watch::SourceWatcher::new(originating_dir, &self.current_dir, options)?;
+        let mut job = CopyJob::new(watch_matches.is_present("overwrite"));
+        visit_dirs_with(originating_dir, &self.current_dir, &mut job)?;
+        watcher.run()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn watch(&self, _watch_matches: &clap::ArgMatches) -> GeneralResult {
+        let message = "Watch mode is only supported on Linux".to_owned();
+        Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+    }
+
+    // What mirroring `destination` against the `sources` copied into it would delete.
+    fn extraneous(&self, sources: &[PathBuf], destination: &Path) -> io::Result<Vec<PathBuf>> {
+        let source_dirs: Vec<PathBuf> = sources.iter().filter(|source| source.is_dir()).cloned().collect();
+        // A destination only holding single files has no copied subtree to mirror.
+        if source_dirs.is_empty() {
+            return Ok(Vec::new())
+        }
+        // The current directory is the whole project, so only the folders copied
+        // into it are mirrored. With --include-root the copy has a folder of its own.
+        let include_top_level = *destination != self.current_dir;
+        mirror::extraneous_paths(&source_dirs, destination, include_top_level)
+    }
+
+    // Remove whatever the copy left in the destination that the source doesn't have.
+    // Returns the paths deleted.
+    fn mirror(
+        &self, sources: &[PathBuf], destination: &Path, journal: Option<&mut hooks::Journal>
+    ) -> Result<Vec<PathBuf>, errors::CupeyError> {
+        let extraneous = self.extraneous(sources, destination)?;
+        if extraneous.is_empty() {
+            return Ok(extraneous)
+        }
+
+        if !self.matches.is_present("yes") && !mirror::confirm_removal(&extraneous)? {
+            println!("Mirror cancelled, nothing was deleted");
+            return Ok(Vec::new())
+        }
+
+        mirror::remove_paths(&extraneous, journal)?;
+        Ok(extraneous)
+    }
+
+    // --mirror --dry-run: lists what would be deleted, nothing is copied or deleted.
+    fn mirror_dry_run(
+        &self, matches: &clap::ArgMatches, settings: &config::Settings, sources: &[&Path],
+        vars: &BTreeMap<String, String>, job: &mut CopyJob
+    ) -> GeneralResult {
+        let mut destinations: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for &source in sources {
+            // With --include-root the folder copied into can be named by a placeholder
+            job.template = if vars.is_empty() { None } else { Some(template::Template::new(vars.clone())) };
+            if let Some(manifest) = manifest::Manifest::load(source)?.filter(|manifest| !manifest.variables.is_empty()) {
+                job.template = Some(template::Template::new(self.manifest_vars(matches, settings, source, &manifest, vars)?));
+            }
+            destinations.entry(job.destination(source, &self.current_dir)?).or_default().push(source.to_owned());
+        }
+
+        let mut extraneous = Vec::new();
+        for (destination, sources) in &destinations {
+            extraneous.extend(self.extraneous(sources, destination)?);
+        }
+        if extraneous.is_empty() {
+            println!("Mirror wouldn't delete anything");
+        } else {
+            println!("Mirror would delete the following from the destination:");
+            for path in &extraneous {
+                println!("  {:?}", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// A flag given on the command line or turned on in the config.
+fn flag_set(matches: &clap::ArgMatches, settings: &config::Settings, name: &str) -> bool {
+    if matches.is_present(name) {
+        return true
+    }
+    let turned_off = NEGATIONS.iter().any(|(setting, negation, _, _)| *setting == name && matches.is_present(negation));
+    !turned_off && settings.flag(name)
+}
+
+// Flags turning a config setting off for one run: setting, flag name, long, help.
+const NEGATIONS: [(&str, &str, &str, &str); 10] = [
+    ("overwrite", "no_overwrite", "no-overwrite", "Don't overwrite existing files, even if the config says to."),
+    ("include_root", "no_include_root", "no-include-root", "Only copy the folder's contents, even if the config says otherwise."),
+    ("preserve_metadata", "no_preserve_metadata", "no-preserve-metadata", "Leave out permissions and times, even if the config keeps them."),
+    ("dart_imports", "no_dart_imports", "no-dart-imports", "Leave imports alone, even if the config fixes them up."),
+    ("no_input", "input", "input", "Ask for template variables, even if the config says not to."),
+    ("no_lock", "lock", "lock", "Record copied files in cupey.lock, even if the config says not to."),
+    ("no_deps", "deps", "deps", "Copy the manifest's dependencies, even if the config says not to."),
+    ("skip_conflicts", "no_skip_conflicts", "no-skip-conflicts", "Write conflict markers, even if the config skips conflicts."),
+    ("rollback", "no_rollback", "no-rollback", "Don't put files back when a hook fails, even if the config says to."),
+    ("no_hooks", "hooks", "hooks", "Run hooks, even if the config turns them off."),
+];
+
+// The flags turning `settings` off, each conflicting with the flag turning it on.
+fn negation_args<'b>(settings: &[&str]) -> Vec<Arg<'b, 'b>> {
+    NEGATIONS.iter()
+        .filter(|(setting, _, _, _)| settings.contains(setting))
+        .map(|(setting, negation, long, help)| Arg::with_name(negation).help(help).long(long).conflicts_with(setting))
+        .collect()
+}
+
+// --profile and --no-config, for every command that reads the config.
+fn config_args<'b>() -> [Arg<'b, 'b>; 2] {
+    [
+        Arg::with_name("profile")
+            .help("Use the settings of a profile from the config.")
+            .long("profile")
+            .value_name("NAME")
+            .takes_value(true),
+        Arg::with_name("no_config")
+            .help("Ignore config files, only use what is given on the command line.")
+            .long("no-config")
+            .conflicts_with("profile"),
+    ]
+}
+
+fn from_arg<'b>() -> Arg<'b, 'b> {
+    Arg::with_name("from_arg")
+        .help("The folder location to copy from")
+        .short("f")
+        .long("from")
+        .value_name("PATH")
+        .takes_value(true)
+        .validator(|value| {
+            let path = Path::new(&value);
+            if path.exists() {
+                Ok(())
+            } else {
+                Err("Sorry this path doesnt't exist".to_owned())
+            }
+        })
 }
 
+/// Settings and bookkeeping shared by every file copied in one run.
+#[derive(Debug, Default)]
+pub struct CopyJob {
+    pub overwrite: bool,
+    /// Present on incremental runs, see `sync::SyncState`.
+    pub sync_state: Option<sync::SyncState>,
+    /// States of the sources copied so far, saved once the run is over unless it was rolled back.
+    pub pending_sync_states: Vec<sync::SyncState>,
+    /// Move files instead of copying them, see `mover`.
+    pub move_files: bool,
+    /// Link to files instead of copying them, see `link::linker`.
+    pub link: Option<link::LinkKind>,
+    /// Copy the source folder itself rather than just its contents.
+    pub include_root: bool,
+    /// Files go into this archive instead of the destination folder.
+    pub archive_writer: Option<archive::ArchiveWriter>,
+    /// Variables substituted in names and contents, see `template::Template`.
+    pub template: Option<template::Template>,
+    /// The `.cupey.toml` of the source being copied.
+    pub manifest: Option<manifest::Manifest>,
+    /// Run over each file's contents after the template, see `transform::Transform`.
+    /// Moving or linking files with transforms is an Unsupported error, and
+    /// incremental runs copy transformed files again even if their source didn't change.
+    pub transforms: Vec<Box<dyn transform::Transform>>,
+    /// Copied Dart files, their imports are fixed up once everything is copied.
+    pub dart_imports: Option<dart::DartImports>,
+    /// Source files never copied, from the config, relative to the folder copied from.
+    pub exclude: Option<globset::GlobSet>,
+    /// The destination's `cupey.lock`, None when nothing is written to disk.
+    pub lock: Option<lock::LockFile>,
+    /// Where the file being copied comes from, only set when there is a lock.
+    pub origin: Option<lock::Origin>,
+    /// What was changed on disk, kept when a failed hook should undo the run.
+    pub journal: Option<hooks::Journal>,
+    /// Run in `hook_dir` after each file is written, see `hooks::Hooks`.
+    pub per_file_hooks: Vec<String>,
+    pub hook_dir: PathBuf,
+    pub report: report::CopyReport,
+}
+
+impl CopyJob {
+    pub fn new(overwrite: bool) -> Self {
+        CopyJob { overwrite, ..Default::default() }
+    }
 
-pub fn visit_dirs(dir: &Path, to_dir: &PathBuf, overwrite: bool) -> GeneralResult {
+    /// Adds `transform` after the ones already registered.
+    pub fn add_transform<T: transform::Transform + 'static>(&mut self, transform: T) {
+        self.transforms.push(Box::new(transform));
+    }
+
+    /// Whether the source file or folder at `relative_path`, or a folder it
+    /// is in, is left out of the copy by the manifest or the config.
+    pub fn excluded(&self, relative_path: &Path) -> bool {
+        // A source's own lock describes that folder, not the copy
+        if self.lock.is_some() && relative_path == Path::new(lock::LOCK_FILE_NAME) {
+            return true
+        }
+        // Archive entries and diffs come one file at a time, not folder by folder
+        relative_path.ancestors()
+            .take_while(|path| !path.as_os_str().is_empty())
+            .any(|path| {
+                self.manifest.as_ref().is_some_and(|manifest| manifest.excludes(path))
+                    || self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(path))
+            })
+    }
+
+    // Notes in the lock what the contents of the file being copied go
+    // through, updates fill in the source's new contents the same way.
+    fn mark_transformed(&mut self, relative_path: &Path) {
+        let vars = match &self.template {
+            Some(template) if self.renders(relative_path) => template.vars.clone(),
+            _ => BTreeMap::new(),
+        };
+        let transformed = !self.transforms.is_empty();
+        if let Some(origin) = self.origin.as_mut() {
+            origin.vars = vars;
+            origin.transformed = transformed;
+        }
+    }
+
+    /// Whether the contents of the file at `relative_path` are filled in from
+    /// the template, the manifest can copy files verbatim.
+    pub fn renders(&self, relative_path: &Path) -> bool {
+        self.template.is_some() && self.manifest.as_ref().is_none_or(|manifest| manifest.renders(relative_path))
+    }
+
+    /// What the contents of the file at `relative_path` go through: the
+    /// template unless the manifest copies the file verbatim, then the
+    /// registered transforms.
+    pub fn transforms_for(&self, relative_path: &Path) -> Vec<&dyn transform::Transform> {
+        let mut transforms: Vec<&dyn transform::Transform> = Vec::new();
+        if let (Some(template), true) = (&self.template, self.renders(relative_path)) {
+            transforms.push(template);
+        }
+        transforms.extend(self.transforms.iter().map(|transform| transform.as_ref()));
+        transforms
+    }
+
+    /// Creates a destination folder if it doesn't exist, or adds it to the
+    /// archive being written.
+    pub fn create_dir(&mut self, dir: &Path) -> GeneralResult {
+        if let Some(writer) = self.archive_writer.as_mut() {
+            return writer.add_dir(dir)
+        }
+        if !dir.exists() {
+            if let Some(journal) = self.journal.as_mut() {
+                journal.before_create_dir(dir);
+            }
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a file in the report and, when it was written, in the lock.
+    pub fn record(&mut self, destination: PathBuf, copied: bool) -> GeneralResult {
+        if copied {
+            for command in &self.per_file_hooks {
+                let env = [
+                    ("CUPEY_DESTINATION", self.hook_dir.display().to_string()),
+                    ("CUPEY_PATH", destination.display().to_string()),
+                ];
+                hooks::run(command, &self.hook_dir, &env, std::slice::from_ref(&destination))?;
+            }
+        }
+        let origin = self.origin.take();
+        if let (true, Some(lock), Some(origin)) = (copied, self.lock.as_mut(), origin) {
+            lock.record(&destination, origin)?;
+        }
+        self.report.record(destination, copied);
+        Ok(())
+    }
+
+    /// File or folder name as it should appear in the destination.
+    pub fn render_name(&self, name: &OsStr, path: &Path) -> Result<OsString, errors::CupeyError> {
+        match &self.template {
+            Some(template) => template.render_name(name, path),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    /// Relative path as it should appear in the destination.
+    pub fn render_path(&self, relative_path: &Path) -> Result<PathBuf, errors::CupeyError> {
+        match &self.template {
+            Some(template) => template.render_path(relative_path),
+            None => Ok(relative_path.to_owned()),
+        }
+    }
+
+    /// Folder the contents of `dir` end up in, `to_dir/<name of dir>` when
+    /// `include_root` is set, otherwise `to_dir` itself.
+    ///
+    /// Archives count as folders, named after the archive minus its
+    /// extension. Other single file sources always land in `to_dir`.
+    pub fn destination(&self, dir: &Path, to_dir: &Path) -> Result<PathBuf, errors::CupeyError> {
+        if self.include_root && dir.is_file() {
+            if let Some(archive_name) = archive::root_name(dir) {
+                return Ok(to_dir.join(self.render_name(&archive_name, dir)?))
+            }
+        }
+        if !self.include_root || dir.is_file() {
+            return Ok(to_dir.to_owned())
+        }
+        // Canonicalize so names like "." or ".." resolve to a real folder name
+        match dir.canonicalize()?.file_name() {
+            Some(dir_name) => Ok(to_dir.join(self.render_name(dir_name, dir)?)),
+            None => {
+                let message = format!("'{}' has no folder name to copy into", dir.to_str().unwrap());
+                Err(errors::CupeyError::new(message, errors::ErrorKind::NoRootName))
+            }
+        }
+    }
+}
+
+
+/// Copies one source into `to_dir`. Folders have their contents copied, see
+/// `visit_dirs_with`, files are copied on their own.
+pub fn copy_source(source: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    if !source.is_file() {
+        return visit_dirs_with(source, to_dir, job)
+    }
+
+    if let Some(kind) = archive::ArchiveKind::from_path(source) {
+        if job.move_files || job.link.is_some() || job.sync_state.is_some() {
+            let message = format!(
+                "'{}' is an archive, its files can't be moved, linked or copied incrementally",
+                source.to_str().unwrap()
+            );
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+        }
+        let to_dir = job.destination(source, to_dir)?;
+        job.create_dir(&to_dir)?;
+        return archive::copy_archive(source, kind, &to_dir, job)
+    }
+
+    job.create_dir(to_dir)?;
+    let relative_path = PathBuf::from(source.file_name().unwrap());
+    copy_file(&source.to_owned(), to_dir, &relative_path, job)
+}
+
+pub fn visit_dirs(dir: &Path, to_dir: &Path, overwrite: bool) -> GeneralResult {
+    visit_dirs_with(dir, to_dir, &mut CopyJob::new(overwrite))
+}
+
+pub fn visit_dirs_with(dir: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    let to_dir = job.destination(dir, to_dir)?;
+    walk(dir, &to_dir, Path::new(""), job)
+}
+
+// relative_dir - path of dir relative to the folder being copied from
+fn walk(dir: &Path, to_dir: &PathBuf, relative_dir: &Path, job: &mut CopyJob) -> GeneralResult {
 
     if dir.is_file(){
         let message = format!("'{}' should not be a file", dir.to_str().unwrap());
@@ -103,12 +1386,20 @@ pub fn visit_dirs(dir: &Path, to_dir: &PathBuf, overwrite: bool) -> GeneralResul
         return Err(errors::CupeyError::new(message, errors::ErrorKind::DirEmpty))
     }
 
+    // Destination folder may not exist yet (e.g. a fresh target folder).
+    job.create_dir(to_dir)?;
+
     // Recurse through folder.
     for entry in fs::read_dir(dir)? {
 
         let entry = entry?;
         let entry_path = entry.path();
 
+        let relative_path = relative_dir.join(entry.file_name());
+        if job.excluded(&relative_path) {
+            continue;
+        }
+
         // block for handling folders
         if entry_path.is_dir() {
 
@@ -116,35 +1407,207 @@ pub fn visit_dirs(dir: &Path, to_dir: &PathBuf, overwrite: bool) -> GeneralResul
             if let Some(folder_name) = entry_path.file_name() {
                 // Create a new directory in destination path
                 let mut new_dest_dir = to_dir.to_owned();
-                new_dest_dir.push(folder_name);
+                new_dest_dir.push(job.render_name(folder_name, &relative_dir.join(folder_name))?);
                 
-                if !new_dest_dir.exists() {
-                    fs::create_dir_all(&new_dest_dir)?;
-                }
+                job.create_dir(&new_dest_dir)?;
                 // Recurse through new directory
-                visit_dirs(&entry_path, &new_dest_dir, overwrite)?;
+                walk(&entry_path, &new_dest_dir, &relative_dir.join(folder_name), job)?;
+
+                // Folders left empty by a move go too, ones holding skipped files stay.
+                if job.move_files && fs::read_dir(&entry_path)?.next().is_none() {
+                    fs::remove_dir(&entry_path)?;
+                }
             }
         
+        // The archive being written may sit inside the source folder
+        } else if job.archive_writer.as_ref().is_some_and(|writer| writer.is_output(&entry_path)) {
+            continue;
+
         // block for handling files     
         } else {
             // println!("Copying file {:?}", &entry_path);
-            let mut new_dest_dir = to_dir.to_owned();
-            copier(&entry_path.to_owned(), &mut new_dest_dir, overwrite)?;
+            copy_file(&entry_path, to_dir, &relative_path, job)?;
         }
     }
 
     Ok(())
 }
 
+fn copy_file(orig_file_path: &PathBuf, to_dir: &Path, relative_path: &Path, job: &mut CopyJob) -> GeneralResult {
+    if !job.transforms.is_empty() && (job.move_files || job.link.is_some()) {
+        let message = format!(
+            "Can't transform '{}', files that are moved or linked are left as they are",
+            orig_file_path.to_str().unwrap()
+        );
+        return Err(errors::CupeyError::new(message, errors::ErrorKind::Unsupported))
+    }
+    let mut destination_dir = to_dir.to_owned();
+    // Transforms get where the file lands, relative_path is where it is in the source
+    let rendered_path = job.render_path(relative_path)?;
+    // The file name is the last part of a file's path
+    let file_name = rendered_path.file_name().unwrap().to_owned();
+    let destination_file_path = to_dir.join(&file_name);
+    if let Some(dart_imports) = job.dart_imports.as_mut() {
+        dart_imports.record(orig_file_path, &destination_file_path)?;
+    }
+    // A moved file has no source left to compare it with, a linked one is its source
+    if job.lock.is_some() && !job.move_files && job.link.is_none() {
+        job.origin = Some(lock::Origin::file(orig_file_path)?);
+    }
+
+    // Templated files are written out from their rendered contents, as are
+    // transformed files going into an archive
+    if job.template.is_some() || job.archive_writer.is_some() && !job.transforms.is_empty() {
+        let mut orig_file = fs::File::open(orig_file_path)?;
+        // Verbatim files only have their name filled in, transforms_for leaves the template out
+        let transforms = job.transforms_for(relative_path);
+        let contents = transform::apply(&transforms, &rendered_path, &mut orig_file)?;
+        job.mark_transformed(relative_path);
+        return write_contents(&mut contents.as_slice(), Path::new(&file_name), to_dir, job)
+    }
+
+    if let Some(writer) = job.archive_writer.as_mut() {
+        let added = writer.append_file(orig_file_path, &destination_file_path)?;
+        job.report.record(destination_file_path, added);
+        return Ok(())
+    }
+    if let Some(journal) = job.journal.as_mut() {
+        journal.before_write(&destination_file_path)?;
+    }
+
+    // Only the registered transforms are left, templates don't combine with the rest
+    job.mark_transformed(relative_path);
+    let transforms: Vec<&dyn transform::Transform> = job.transforms.iter().map(|transform| transform.as_ref()).collect();
+    let state = match job.sync_state.as_mut() {
+        Some(state) => state,
+        None => {
+            let copied = if job.move_files {
+                mover(orig_file_path, &mut destination_dir, job.overwrite)?
+            } else if let Some(kind) = job.link {
+                link::linker(orig_file_path, &mut destination_dir, job.overwrite, kind)?
+            } else {
+                copier_with(orig_file_path, &mut destination_dir, job.overwrite, &rendered_path, &transforms)?
+            };
+            job.record(destination_file_path, copied)?;
+            return Ok(())
+        }
+    };
+
+    let action = match state.check(relative_path, orig_file_path, &destination_file_path)? {
+        // The state only knows the source, it can't tell whether the transforms changed
+        sync::SyncAction::UpToDate if !transforms.is_empty() => sync::SyncAction::Copy,
+        action => action,
+    };
+    let overwrite = match action {
+        sync::SyncAction::UpToDate => {
+            println!("Up to date: {:?}", relative_path);
+            job.report.up_to_date.push(destination_file_path);
+            return Ok(())
+        },
+        sync::SyncAction::LocallyModified if !job.overwrite => {
+            println!("Moving on, file modified since last sync: {:?}", &destination_file_path);
+            job.report.skipped.push(destination_file_path);
+            return Ok(())
+        },
+        sync::SyncAction::LocallyModified => true,
+        // A recorded destination file is our own earlier copy, safe to replace.
+        sync::SyncAction::Copy => job.overwrite || state.get(relative_path).is_some(),
+    };
+
+    let copied = copier_with(orig_file_path, &mut destination_dir, overwrite, &rendered_path, &transforms)?;
+    if copied {
+        state.record(relative_path, orig_file_path, &destination_file_path)?;
+    }
+    job.record(destination_file_path, copied)?;
+    Ok(())
+}
+
 // orig_file_path - originating file path
-pub fn copier(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite: bool) -> GeneralResult {
+// Returns false when an existing file was left alone.
+pub fn copier(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite: bool) -> Result<bool, errors::CupeyError> {
+    let file_name = Path::new(orig_file_path.file_name().unwrap());
+    copier_with(orig_file_path, destination_dir, overwrite, file_name, &[])
+}
+
+// Same as copier, with the contents run through transforms in order first.
+// relative_path - where the file lands relative to the destination, handed to the transforms
+pub fn copier_with(
+    orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite: bool, relative_path: &Path,
+    transforms: &[&dyn transform::Transform]
+) -> Result<bool, errors::CupeyError> {
     // Get file name to append to new destination path
     let file_name = orig_file_path.file_name().unwrap();
     destination_dir.push(file_name);
     // Change name for readability sakes.
     let destination_file_path = destination_dir;
 
-    let mut dest_file;
+    let mut file_to_copy = fs::OpenOptions::new()
+        .read(true)
+        .open(orig_file_path)?;
+
+    // Transformed before the destination is opened, a failing transform leaves it alone
+    let contents = transform::apply(transforms, relative_path, &mut file_to_copy)?;
+
+    let mut dest_file = match open_destination(destination_file_path, overwrite)? {
+        Some(dest_file) => dest_file,
+        None => return Ok(false),
+    };
+
+    dest_file.write_all(&contents)?;
+
+    println!("Copied {:?} successfully", orig_file_path.as_path().file_name().unwrap());
+
+    Ok(true)
+}
+
+// Streams a file that isn't on disk (an archive entry, a git blob) to its
+// destination, following the same overwrite rules as copier and running it
+// through the job's template and transforms if there are any.
+pub(crate) fn copy_contents(entry: &mut dyn Read, relative_path: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    // Transforms need the whole file, otherwise it is streamed as is.
+    let transforms = job.transforms_for(relative_path);
+    if transforms.is_empty() {
+        return write_contents(entry, relative_path, to_dir, job)
+    }
+    let contents = transform::apply(&transforms, relative_path, entry)?;
+    job.mark_transformed(relative_path);
+    write_contents(&mut contents.as_slice(), relative_path, to_dir, job)
+}
+
+// copy_contents without any rendering.
+pub(crate) fn write_contents(entry: &mut dyn Read, relative_path: &Path, to_dir: &Path, job: &mut CopyJob) -> GeneralResult {
+    let destination_file_path = to_dir.join(relative_path);
+
+    if let Some(writer) = job.archive_writer.as_mut() {
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        let added = writer.append_bytes(&contents, &destination_file_path)?;
+        job.report.record(destination_file_path, added);
+        return Ok(())
+    }
+
+    if let Some(parent) = destination_file_path.parent() {
+        job.create_dir(parent)?;
+    }
+    if let Some(journal) = job.journal.as_mut() {
+        journal.before_write(&destination_file_path)?;
+    }
+
+    let copied = match open_destination(&destination_file_path, job.overwrite)? {
+        Some(mut dest_file) => {
+            std::io::copy(entry, &mut dest_file)?;
+            println!("Copied {:?} successfully", relative_path);
+            true
+        },
+        None => false,
+    };
+    job.record(destination_file_path, copied)?;
+    Ok(())
+}
+
+// Opens a file for copying into, None when it exists and shouldn't be overwritten.
+pub(crate) fn open_destination(destination_file_path: &Path, overwrite: bool) -> Result<Option<fs::File>, errors::CupeyError> {
+    let dest_file;
     if destination_file_path.exists() {
         // Overwrite existing file 
         if overwrite {
@@ -155,7 +1618,7 @@ pub fn copier(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite
                 .open(destination_file_path)?;
         } else {
             println!("Moving on, file exists: {:?}", &destination_file_path);
-            return Ok(())
+            return Ok(None)
         }
     } else {
         // Create new file
@@ -164,20 +1627,73 @@ pub fn copier(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite
             .create_new(true)
             .open(destination_file_path)?;   
     }
+    Ok(Some(dest_file))
+}
 
-    let mut file_to_copy = fs::OpenOptions::new()
-        .read(true)
-        .open(orig_file_path)?;
-    
-    let mut contents = Vec::new();  // Create Vec<u8> bytes buffer
-    
-    file_to_copy.read_to_end(&mut contents)?;
+// Same as copier, but the original file is gone afterwards.
+// Renames when possible, across filesystems the file is copied, checked and then deleted.
+pub fn mover(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite: bool) -> Result<bool, errors::CupeyError> {
+    let destination_file_path = destination_dir.join(orig_file_path.file_name().unwrap());
+    if destination_file_path.exists() && !overwrite {
+        println!("Moving on, file exists: {:?}", &destination_file_path);
+        return Ok(false)
+    }
 
-    dest_file.write_all(&mut contents)?;
+    match fs::rename(orig_file_path, &destination_file_path) {
+        Ok(()) => {
+            println!("Moved {:?} successfully", orig_file_path.as_path().file_name().unwrap());
+            return Ok(true)
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {},
+        Err(err) => return Err(err.into()),
+    }
 
-    println!("Copied {:?} successfully", orig_file_path.as_path().file_name().unwrap());
+    copier(orig_file_path, destination_dir, overwrite)?;
+    if sync::hash_file(orig_file_path)? != sync::hash_file(&destination_file_path)? {
+        let message = format!(
+            "'{}' doesn't match its copy, the original was not deleted",
+            orig_file_path.to_str().unwrap()
+        );
+        return Err(errors::CupeyError::new(message, errors::ErrorKind::VerifyFailed))
+    }
+    fs::remove_file(orig_file_path)?;
 
-    Ok(())
+    Ok(true)
+}
+
+/// Folder where cupey keeps its own files, `$CUPEY_HOME` or `~/.cupey`.
+pub fn cupey_home() -> PathBuf {
+    if let Some(home) = env::var_os("CUPEY_HOME") {
+        return PathBuf::from(home)
+    }
+    let user_home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    user_home.join(".cupey")
+}
+
+// Asks `question` and reads the answer from stdin. Anything other than "y" or
+// "yes" (including end of input) counts as a no.
+pub(crate) fn ask_yes_no(question: &str) -> io::Result<bool> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write!(out, "{} [y/N] ", question)?;
+    out.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+// Path with forward slashes whatever the platform, how paths are written in
+// the lock, in Dart uris, in zip entries and wherever they are shown.
+pub(crate) fn forward_slashes(path: &Path) -> String {
+    let parts: Vec<String> = path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    parts.join("/")
 }
 
 pub fn empty_dir(dir: &Path) -> bool {
@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{copier, errors};
+
+
+/// How destination files point back at their source in link mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkKind {
+    Hard,
+    /// Symlink to the absolute source path.
+    Symlink,
+    /// Symlink to the source path relative to the link, survives moving both trees together.
+    RelativeSymlink,
+}
+
+impl FromStr for LinkKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hard" => Ok(LinkKind::Hard),
+            "symlink" => Ok(LinkKind::Symlink),
+            "relative-symlink" => Ok(LinkKind::RelativeSymlink),
+            _ => Err(format!("Sorry '{}' isn't a link kind, use hard, symlink or relative-symlink", value)),
+        }
+    }
+}
+
+// Same as copier, but creates a link to the original file instead of a copy.
+// Hardlinks that would cross filesystems fall back to copying.
+pub fn linker(orig_file_path: &PathBuf, destination_dir: &mut PathBuf, overwrite: bool, kind: LinkKind) -> Result<bool, errors::CupeyError> {
+    let destination_file_path = destination_dir.join(orig_file_path.file_name().unwrap());
+    // symlink_metadata so dangling links count as existing
+    if fs::symlink_metadata(&destination_file_path).is_ok() {
+        if !overwrite {
+            println!("Moving on, file exists: {:?}", &destination_file_path);
+            return Ok(false)
+        }
+        fs::remove_file(&destination_file_path)?;
+    }
+
+    let orig_file_path_abs = orig_file_path.canonicalize()?;
+    let result = match kind {
+        LinkKind::Hard => fs::hard_link(&orig_file_path_abs, &destination_file_path),
+        LinkKind::Symlink => symlink_file(&orig_file_path_abs, &destination_file_path),
+        LinkKind::RelativeSymlink => {
+            let link_dir = destination_dir.canonicalize()?;
+            symlink_file(&relative_path(&link_dir, &orig_file_path_abs), &destination_file_path)
+        },
+    };
+
+    match result {
+        Ok(()) => {
+            println!("Linked {:?} successfully", orig_file_path.as_path().file_name().unwrap());
+            Ok(true)
+        },
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            copier(orig_file_path, destination_dir, true)
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn symlink_file(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub(crate) fn symlink_file(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Path leading from the `from_dir` folder to `to`, both should be absolute.
+pub fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
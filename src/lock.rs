@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{archive, errors, git, sync, template, GeneralResult};
+
+
+/// Name of the lock file kept in the destination directory.
+pub const LOCK_FILE_NAME: &str = "cupey.lock";
+
+const LOCK_HEADER: &str = "# Written by cupey: where each copied file came from. Safe to commit, don't edit by hand.\n\n";
+
+// Every lock saved on this machine, one path per line, kept with the bases.
+const LOCK_LIST_NAME: &str = "locks";
+
+// Bases younger than this are never pruned, another run may be about to save a lock using them.
+const BASE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Where one copied file came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockEntry {
+    /// Destination path relative to the lock file's folder, always with forward slashes.
+    pub path: String,
+    /// The source file, or the archive or repository it was read from.
+    pub source: PathBuf,
+    /// Path inside the archive or repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inner_path: Option<PathBuf>,
+    /// Registered name of the folder the source is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
+    /// Revision asked for with --rev.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// Commit the file was read at, or the source repository's HEAD when it was copied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// sha256 of the source contents.
+    pub source_hash: String,
+    /// sha256 of the file as the run left it.
+    pub hash: String,
+    /// sha256 of what cupey wrote, when import rewrites or hooks changed the
+    /// file afterwards. Updates merge against it, the changes count as local edits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_hash: Option<String>,
+    /// Template variables the contents were filled in with, updates fill in
+    /// the source's new contents with them too.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    /// Run through transforms, what was written can't be worked out from the
+    /// source alone.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub transformed: bool,
+    /// An update wrote conflict markers into the file, it stays conflicted
+    /// until they are gone.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub conflicted: bool,
+    /// UTC, e.g. 2024-05-01T09:30:00Z.
+    pub copied_at: String,
+}
+
+impl LockEntry {
+    /// Hash of the contents cupey wrote, what updates merge against.
+    pub fn base_hash(&self) -> &str {
+        self.written_hash.as_deref().unwrap_or(&self.hash)
+    }
+
+    /// What the source holds now, None when the file is gone from it.
+    ///
+    /// Files from a repository are read at the revision they were copied
+    /// with, so a branch picks up new commits and a commit id never changes.
+    pub fn upstream_contents(&self) -> Result<Option<Vec<u8>>, errors::CupeyError> {
+        let inner_path = match &self.inner_path {
+            Some(inner_path) => inner_path,
+            None if self.source.is_file() => return Ok(Some(fs::read(&self.source)?)),
+            None => return Ok(None),
+        };
+        if !self.source.exists() {
+            Ok(None)
+        } else if self.source.is_dir() {
+            git::read_file(&self.source, self.rev.as_deref().unwrap_or("HEAD"), inner_path)
+        } else {
+            archive::read_entry(&self.source, inner_path)
+        }
+    }
+}
+
+/// Where the file being copied comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub source: PathBuf,
+    pub inner_path: Option<PathBuf>,
+    pub source_hash: String,
+    /// Set once the contents are filled in from a template.
+    pub vars: BTreeMap<String, String>,
+    /// Set once the contents go through transforms.
+    pub transformed: bool,
+}
+
+impl Origin {
+    /// A file on disk.
+    pub fn file(path: &Path) -> std::io::Result<Self> {
+        Ok(Origin {
+            source: path.canonicalize()?,
+            inner_path: None,
+            source_hash: sync::hash_file(path)?,
+            vars: BTreeMap::new(),
+            transformed: false,
+        })
+    }
+
+    /// An archive entry or git blob.
+    pub fn inner(container: &Path, inner_path: &Path, contents: &[u8]) -> std::io::Result<Self> {
+        Ok(Origin {
+            source: container.canonicalize()?,
+            inner_path: Some(inner_path.to_owned()),
+            source_hash: sync::hash_bytes(contents),
+            vars: BTreeMap::new(),
+            transformed: false,
+        })
+    }
+}
+
+/// What holds for every file of the source being copied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceInfo {
+    pub library: Option<String>,
+    pub rev: Option<String>,
+    pub revision: Option<String>,
+}
+
+// The file as written.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LockFileContents {
+    #[serde(default)]
+    file: Vec<LockEntry>,
+}
+
+/// The destination's `cupey.lock`, one entry per copied file.
+///
+/// Entries of files copied again are replaced, others are kept until the
+/// file they describe is deleted.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+    root: PathBuf,
+    pub entries: BTreeMap<String, LockEntry>,
+    /// Applies to the files recorded next.
+    pub source_info: SourceInfo,
+}
+
+impl LockFile {
+    /// Loads `dir`'s lock, starting empty if there is none yet.
+    pub fn load(dir: &Path) -> Result<Self, errors::CupeyError> {
+        let path = dir.join(LOCK_FILE_NAME);
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            let contents: LockFileContents = toml::from_str(&fs::read_to_string(&path)?).map_err(|err| {
+                let message = format!("Couldn't read '{}': {}", path.to_str().unwrap(), err);
+                errors::CupeyError::new(message, errors::ErrorKind::LockError)
+            })?;
+            for entry in contents.file {
+                entries.insert(entry.path.clone(), entry);
+            }
+        }
+        Ok(LockFile { path, root: dir.to_owned(), entries, source_info: SourceInfo::default() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The folder entry paths are relative to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Records `destination` as just written from `origin`. Files outside the
+    /// lock's folder aren't recorded.
+    pub fn record(&mut self, destination: &Path, origin: Origin) -> std::io::Result<()> {
+        let path = match self.relative(destination) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let entry = LockEntry {
+            path: path.clone(),
+            source: origin.source,
+            inner_path: origin.inner_path,
+            library: self.source_info.library.clone(),
+            rev: self.source_info.rev.clone(),
+            revision: self.source_info.revision.clone(),
+            source_hash: origin.source_hash,
+            hash: keep_base(&fs::read(destination)?)?,
+            written_hash: None,
+            vars: origin.vars,
+            transformed: origin.transformed,
+            conflicted: false,
+            copied_at: now(),
+        };
+        self.entries.insert(path, entry);
+        Ok(())
+    }
+
+    /// Updates the recorded hash of files changed after they were copied,
+    /// keeping what cupey wrote as the base.
+    pub fn rehash(&mut self, destinations: &[PathBuf]) -> std::io::Result<()> {
+        for destination in destinations {
+            if let Some(path) = self.relative(destination) {
+                if let Some(entry) = self.entries.get_mut(&path) {
+                    let hash = sync::hash_file(destination)?;
+                    if entry.written_hash.is_none() && hash != entry.hash {
+                        entry.written_hash = Some(entry.hash.clone());
+                    }
+                    entry.hash = hash;
+                    if entry.written_hash.as_deref() == Some(entry.hash.as_str()) {
+                        entry.written_hash = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that the file at `path` now has `upstream`'s changes, which
+    /// becomes the base the next update merges against. Anything else in the
+    /// file counts as a local edit from now on.
+    ///
+    /// `written` is `upstream` filled in with the entry's variables, and
+    /// `conflicted` whether it came with conflict markers, see `LockEntry::conflicted`.
+    pub fn record_update(&mut self, path: &str, upstream: &[u8], written: &[u8], conflicted: bool) -> std::io::Result<()> {
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.hash = keep_base(written)?;
+            entry.source_hash = sync::hash_bytes(upstream);
+            entry.written_hash = None;
+            entry.conflicted = conflicted;
+            entry.copied_at = now();
+        }
+        Ok(())
+    }
+
+    /// The entry for a destination file, if it was copied by cupey.
+    pub fn get(&self, destination: &Path) -> Option<&LockEntry> {
+        self.entries.get(&self.relative(destination)?)
+    }
+
+    pub fn full_path(&self, entry: &LockEntry) -> PathBuf {
+        self.root.join(entry.path.split('/').collect::<PathBuf>())
+    }
+
+    fn relative(&self, destination: &Path) -> Option<String> {
+        Some(crate::forward_slashes(destination.strip_prefix(&self.root).ok()?))
+    }
+
+    /// Writes the lock back, replacing the old one in a single rename so it is
+    /// never left half written.
+    ///
+    /// Entries for files that no longer exist are dropped.
+    pub fn save(&mut self) -> GeneralResult {
+        let root = self.root.clone();
+        self.entries.retain(|path, _| root.join(path.split('/').collect::<PathBuf>()).exists());
+
+        let contents = LockFileContents { file: self.entries.values().cloned().collect() };
+        let text = toml::to_string(&contents).map_err(|err| {
+            let message = format!("Couldn't write '{}': {}", self.path.to_str().unwrap(), err);
+            errors::CupeyError::new(message, errors::ErrorKind::LockError)
+        })?;
+
+        let temp_path = self.path.with_extension("lock.tmp");
+        fs::write(&temp_path, format!("{}{}", LOCK_HEADER, text))?;
+        fs::rename(&temp_path, &self.path)?;
+        prune_bases(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Contents of a file as cupey wrote it, by the hash in its lock entry.
+///
+/// Kept in `<cupey home>/bases` so updates have something to merge against,
+/// None when this machine never wrote it or it isn't text.
+pub fn load_base(hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+    let path = base_path(hash);
+    if path.is_file() {
+        Ok(Some(fs::read(path)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Stores text contents by their hash, returning the hash. Only text can be
+// merged, other files aren't kept.
+fn keep_base(contents: &[u8]) -> std::io::Result<String> {
+    let hash = sync::hash_bytes(contents);
+    let is_text = !template::is_binary(contents) && std::str::from_utf8(contents).is_ok();
+    let path = base_path(&hash);
+    if is_text && !path.exists() {
+        // parent always exists, the path has at least 2 components
+        fs::create_dir_all(path.parent().unwrap())?;
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &path)?;
+    }
+    Ok(hash)
+}
+
+// Notes that the lock at `lock_path` uses bases, then deletes the bases no
+// lock saved on this machine refers to any more.
+fn prune_bases(lock_path: &Path) -> std::io::Result<()> {
+    let bases_dir = crate::cupey_home().join("bases");
+    if !bases_dir.is_dir() {
+        return Ok(())
+    }
+    let list_path = bases_dir.join(LOCK_LIST_NAME);
+    let mut lock_paths: BTreeSet<PathBuf> = match fs::read_to_string(&list_path) {
+        Ok(list) => list.lines().map(PathBuf::from).collect(),
+        Err(_) => BTreeSet::new(),
+    };
+    lock_paths.insert(lock_path.canonicalize()?);
+
+    let mut referenced = HashSet::new();
+    let mut readable = true;
+    lock_paths.retain(|path| {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            // Deleted along with its project
+            Err(_) => return false,
+        };
+        match toml::from_str::<LockFileContents>(&text) {
+            Ok(contents) => referenced.extend(contents.file.iter().map(|entry| entry.base_hash().to_owned())),
+            Err(_) => readable = false,
+        }
+        true
+    });
+
+    let list: String = lock_paths.iter().map(|path| format!("{}\n", path.display())).collect();
+    let temp_path = list_path.with_extension("tmp");
+    fs::write(&temp_path, list)?;
+    fs::rename(&temp_path, &list_path)?;
+    // A lock that can't be read may still need any of them
+    if !readable {
+        return Ok(())
+    }
+
+    for dir in fs::read_dir(&bases_dir)? {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for base in fs::read_dir(&dir)? {
+            let base = base?;
+            let hash = format!("{}{}", dir.file_name().unwrap().to_string_lossy(), base.file_name().to_string_lossy());
+            let age = base.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if !referenced.contains(&hash) && age > BASE_GRACE {
+                fs::remove_file(base.path())?;
+            }
+        }
+        // Only goes when it is empty
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(())
+}
+
+// Split on the first two characters like git does, to keep folders small.
+fn base_path(hash: &str) -> PathBuf {
+    let (dir, name) = hash.split_at(2.min(hash.len()));
+    crate::cupey_home().join("bases").join(dir).join(name)
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second) = archive::civil_date_time(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
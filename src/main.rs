@@ -4,6 +4,6 @@ use Cupey::Cupey;
 fn main() {
 
     let cupey = Cupey::new();
-    cupey.copy_files().unwrap_or_else(|e| e.exit())
+    cupey.run().unwrap_or_else(|e| e.exit())
 
 }
\ No newline at end of file
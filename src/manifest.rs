@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{errors, hooks, pubspec};
+
+
+/// Name of the manifest file a source folder can carry.
+pub const MANIFEST_FILE_NAME: &str = ".cupey.toml";
+
+// The file as written, before globs and regexes are compiled.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ManifestFile {
+    description: Option<String>,
+    dependencies: Vec<String>,
+    variables: BTreeMap<String, VariableFile>,
+    exclude: Vec<String>,
+    templates: Vec<String>,
+    verbatim: Vec<String>,
+    messages: Vec<String>,
+    pubspec: pubspec::PubspecRequirements,
+    hooks: hooks::Hooks,
+    // Settings for copying into the folder, see config::Config
+    #[serde(rename = "defaults")]
+    _defaults: toml::Table,
+    #[serde(rename = "profiles")]
+    _profiles: toml::Table,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct VariableFile {
+    description: Option<String>,
+    default: Option<String>,
+    pattern: Option<String>,
+}
+
+/// A variable the source folder's templates need.
+#[derive(Debug)]
+pub struct Variable {
+    pub description: Option<String>,
+    pub default: Option<String>,
+    /// Regex the whole value has to match.
+    pub pattern: Option<String>,
+    regex: Option<Regex>,
+}
+
+/// What a source folder's `.cupey.toml` says about copying it:
+///
+/// ```toml
+/// description = "A button that glows when pressed"
+/// dependencies = ["theme_colors"]
+/// exclude = ["*.g.dart", "build"]
+/// verbatim = ["assets/**"]
+/// messages = ["Run flutter pub get to finish setting up {{name}}"]
+///
+/// [hooks]
+/// post = ["dart format {{name}}"]
+///
+/// [pubspec]
+/// dependencies = { provider = "^6.0.0" }
+/// assets = ["assets/{{name}}/"]
+///
+/// [variables.name]
+/// description = "Widget name in snake_case"
+/// default = "my_widget"
+/// pattern = "[a-z][a-z0-9_]*"
+/// ```
+///
+/// File patterns are globs matched against paths relative to the source
+/// folder, `*` matches across folders too.
+#[derive(Debug)]
+pub struct Manifest {
+    /// What the source folder holds, shown by `cupey list`.
+    pub description: Option<String>,
+    /// Other sources `cupey use` copies along with this one, paths inside the same
+    /// registered folder like `theme/colors.dart` or registered names.
+    pub dependencies: Vec<String>,
+    pub variables: BTreeMap<String, Variable>,
+    /// Printed once copying is done, placeholders filled in.
+    pub messages: Vec<String>,
+    /// What the destination's `pubspec.yaml` needs, from the `[pubspec]`
+    /// table and the `cupey.pubspec.yaml` sidecar.
+    pub pubspec: pubspec::PubspecRequirements,
+    /// Run when the folder is copied, placeholders filled in.
+    pub hooks: hooks::Hooks,
+    exclude: GlobSet,
+    // Only these files have their contents rendered, all text files if None.
+    templates: Option<GlobSet>,
+    verbatim: GlobSet,
+}
+
+impl Manifest {
+    /// Reads `dir`'s manifest, None when it has neither a manifest nor a pubspec fragment.
+    pub fn load(dir: &Path) -> Result<Option<Self>, errors::CupeyError> {
+        let path = dir.join(MANIFEST_FILE_NAME);
+        let fragment_path = dir.join(pubspec::FRAGMENT_FILE_NAME);
+        let mut manifest = match (path.is_file(), fragment_path.is_file()) {
+            (false, false) => return Ok(None),
+            (true, _) => Self::parse(&fs::read_to_string(&path)?, &path)?,
+            (false, true) => Self::parse("", &path)?,
+        };
+        if fragment_path.is_file() {
+            let fragment = pubspec::PubspecRequirements::from_fragment(&fs::read_to_string(&fragment_path)?, &fragment_path)?;
+            manifest.pubspec.extend(fragment);
+        }
+        Ok(Some(manifest))
+    }
+
+    /// `path` is only used in error messages.
+    pub fn parse(text: &str, path: &Path) -> Result<Self, errors::CupeyError> {
+        let file: ManifestFile = toml::from_str(text)
+            .map_err(|err| manifest_error(path, &err.to_string()))?;
+
+        let mut variables = BTreeMap::new();
+        for (name, variable) in file.variables {
+            let regex = match &variable.pattern {
+                Some(pattern) => Some(
+                    Regex::new(&format!("^(?:{})$", pattern))
+                        .map_err(|err| manifest_error(path, &err.to_string()))?
+                ),
+                None => None,
+            };
+            variables.insert(name, Variable {
+                description: variable.description, default: variable.default, pattern: variable.pattern, regex
+            });
+        }
+
+        let templates = if file.templates.is_empty() {
+            None
+        } else {
+            Some(glob_set(&file.templates, path)?)
+        };
+
+        Ok(Manifest {
+            description: file.description,
+            dependencies: file.dependencies,
+            variables,
+            messages: file.messages,
+            pubspec: file.pubspec,
+            hooks: file.hooks,
+            exclude: glob_set(&file.exclude, path)?,
+            templates,
+            verbatim: glob_set(&file.verbatim, path)?,
+        })
+    }
+
+    /// Whether the file or folder at `relative_path` is left out of the copy.
+    /// The manifest and pubspec fragment themselves always are.
+    pub fn excludes(&self, relative_path: &Path) -> bool {
+        relative_path == Path::new(MANIFEST_FILE_NAME)
+            || relative_path == Path::new(pubspec::FRAGMENT_FILE_NAME)
+            || self.exclude.is_match(relative_path)
+    }
+
+    /// Whether the file at `relative_path` has its contents rendered.
+    pub fn renders(&self, relative_path: &Path) -> bool {
+        let template = self.templates.as_ref().is_none_or(|templates| templates.is_match(relative_path));
+        template && !self.verbatim.is_match(relative_path)
+    }
+
+    /// Fills in every declared variable not in `given`, asking on `prompt`
+    /// when there is one and falling back to defaults otherwise.
+    ///
+    /// Values are checked against the variable's pattern, the result holds
+    /// `given` too.
+    pub fn resolve_vars(
+        &self, given: &BTreeMap<String, String>, mut prompt: Option<&mut dyn BufRead>
+    ) -> Result<BTreeMap<String, String>, errors::CupeyError> {
+        let mut vars = given.clone();
+
+        for (name, variable) in &self.variables {
+            if let Some(value) = given.get(name) {
+                if !variable.matches(value) {
+                    let message = format!("'{}' isn't a valid value for {}, {}", value, name, variable.expected());
+                    return Err(errors::CupeyError::new(message, errors::ErrorKind::TemplateError))
+                }
+                continue;
+            }
+
+            let value = match prompt.as_mut() {
+                Some(input) => ask(name, variable, &mut **input)?,
+                None => match &variable.default {
+                    Some(default) => default.to_owned(),
+                    None => {
+                        let message = format!(
+                            "Missing variable '{}'{}, pass it with --var {}=<value>",
+                            name, variable.description.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default(), name
+                        );
+                        return Err(errors::CupeyError::new(message, errors::ErrorKind::TemplateError))
+                    },
+                },
+            };
+            vars.insert(name.to_owned(), value);
+        }
+        Ok(vars)
+    }
+}
+
+impl Variable {
+    pub fn matches(&self, value: &str) -> bool {
+        self.regex.as_ref().is_none_or(|regex| regex.is_match(value))
+    }
+
+    fn expected(&self) -> String {
+        match &self.pattern {
+            Some(pattern) => format!("expected something matching {}", pattern),
+            None => "expected any value".to_owned(),
+        }
+    }
+}
+
+// Asks until a valid value is given, an empty answer takes the default.
+fn ask(name: &str, variable: &Variable, input: &mut dyn BufRead) -> Result<String, errors::CupeyError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    loop {
+        write!(out, "{}", variable.description.as_deref().unwrap_or(name))?;
+        if let Some(default) = &variable.default {
+            write!(out, " [{}]", default)?;
+        }
+        write!(out, ": ")?;
+        out.flush()?;
+
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            let message = format!("No value given for variable '{}'", name);
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::TemplateError))
+        }
+        let answer = match (answer.trim(), &variable.default) {
+            ("", Some(default)) => default.to_owned(),
+            (answer, _) => answer.to_owned(),
+        };
+
+        if !answer.is_empty() && variable.matches(&answer) {
+            return Ok(answer)
+        }
+        writeln!(out, "Sorry '{}' isn't valid, {}", answer, variable.expected())?;
+    }
+}
+
+fn glob_set(patterns: &[String], path: &Path) -> Result<GlobSet, errors::CupeyError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|err| manifest_error(path, &err.to_string()))?);
+    }
+    builder.build().map_err(|err| manifest_error(path, &err.to_string()))
+}
+
+fn manifest_error(path: &Path, err: &str) -> errors::CupeyError {
+    let message = format!("Couldn't read '{}': {}", path.to_str().unwrap(), err);
+    errors::CupeyError::new(message, errors::ErrorKind::ManifestError)
+}
@@ -0,0 +1,79 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{hooks, lock, manifest, GeneralResult};
+
+
+// Never deleted, wherever they are: the project's history, cupey's config and
+// manifests, and the lock.
+const KEPT_NAMES: [&str; 3] = [".git", manifest::MANIFEST_FILE_NAME, lock::LOCK_FILE_NAME];
+
+/// Walks `to_dir` and collects every file or folder that has no counterpart
+/// in any of `from_dirs`.
+///
+/// Without `include_top_level` only folders `from_dirs` also have are looked
+/// into, so copying a folder's contents straight into a project doesn't delete
+/// the rest of the project. Extraneous folders are returned as a single entry,
+/// their contents are not listed separately since removing the folder removes
+/// them too.
+pub fn extraneous_paths(from_dirs: &[PathBuf], to_dir: &Path, include_top_level: bool) -> io::Result<Vec<PathBuf>> {
+    let mut extraneous = Vec::new();
+    if to_dir.is_dir() {
+        collect(from_dirs, to_dir, include_top_level, &mut extraneous)?;
+    }
+    extraneous.sort();
+    Ok(extraneous)
+}
+
+fn collect(from_dirs: &[PathBuf], to_dir: &Path, listed: bool, extraneous: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(to_dir)? {
+        let entry = entry?;
+        if KEPT_NAMES.iter().any(|name| entry.file_name() == *name) {
+            continue
+        }
+        let entry_path = entry.path();
+        let source_paths: Vec<PathBuf> = from_dirs.iter()
+            .map(|from_dir| from_dir.join(entry.file_name()))
+            .filter(|source_path| source_path.exists())
+            .collect();
+
+        if source_paths.is_empty() {
+            if listed {
+                extraneous.push(entry_path);
+            }
+        } else if entry_path.is_dir() {
+            let source_dirs: Vec<PathBuf> = source_paths.into_iter().filter(|path| path.is_dir()).collect();
+            collect(&source_dirs, &entry_path, true, extraneous)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the given paths, folders are removed along with their contents.
+///
+/// With a journal each one is backed up first, so a rollback can restore it.
+pub fn remove_paths(paths: &[PathBuf], mut journal: Option<&mut hooks::Journal>) -> GeneralResult {
+    for path in paths {
+        if let Some(journal) = journal.as_mut() {
+            journal.before_remove(path)?;
+        }
+        // symlink_metadata so a link to a folder is removed, not followed.
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        println!("Removed {:?}", path);
+    }
+    Ok(())
+}
+
+/// Lists the paths about to be deleted and asks the user to confirm.
+pub fn confirm_removal(paths: &[PathBuf]) -> io::Result<bool> {
+    println!("Mirror will delete the following from the destination:");
+    for path in paths {
+        println!("  {:?}", path);
+    }
+    crate::ask_yes_no(&format!("Delete {} item(s)?", paths.len()))
+}
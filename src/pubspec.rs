@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use crate::{errors, template};
+
+
+/// Sidecar file a source folder can list its pubspec requirements in, instead
+/// of the manifest's `[pubspec]` table.
+pub const FRAGMENT_FILE_NAME: &str = "cupey.pubspec.yaml";
+
+/// Packages and assets a source needs in the `pubspec.yaml` of the project it is copied into.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PubspecRequirements {
+    /// Package name -> version constraint, e.g. "provider" -> "^6.0.0".
+    pub dependencies: BTreeMap<String, String>,
+    pub dev_dependencies: BTreeMap<String, String>,
+    /// Entries for `flutter: assets:`.
+    pub assets: Vec<String>,
+}
+
+/// A package the project already depends on with a constraint no version
+/// satisfies together with the one required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub package: String,
+    pub existing: String,
+    pub required: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}: pubspec.yaml has {}, {} is needed", self.package, self.existing, self.required)
+    }
+}
+
+/// What merging changed.
+#[derive(Debug, Default)]
+pub struct MergeOutcome {
+    pub pubspec: String,
+    /// Lines added, as they read in the file.
+    pub added: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl PubspecRequirements {
+    pub fn is_empty(&self) -> bool {
+        self.dependencies.is_empty() && self.dev_dependencies.is_empty() && self.assets.is_empty()
+    }
+
+    /// Reads a pubspec fragment: `dependencies:` and `dev_dependencies:` holding
+    /// version constraints and `flutter: assets:`, anything else is an error.
+    pub fn from_fragment(text: &str, path: &Path) -> Result<Self, errors::CupeyError> {
+        let mut requirements = PubspecRequirements::default();
+        let mut section = "";
+
+        for (index, line) in text.lines().enumerate() {
+            let content = strip_comment(line);
+            if content.trim().is_empty() {
+                continue;
+            }
+            if !content.starts_with(char::is_whitespace) {
+                section = match content.trim_end() {
+                    "dependencies:" | "dev_dependencies:" | "flutter:" => content.trim_end().trim_end_matches(':'),
+                    _ => return Err(fragment_error(path, index)),
+                };
+                continue;
+            }
+
+            let item = content.trim();
+            match section {
+                "flutter" if item == "assets:" => {},
+                "flutter" => match item.strip_prefix("- ") {
+                    Some(asset) => requirements.assets.push(unquote(asset).to_owned()),
+                    None => return Err(fragment_error(path, index)),
+                },
+                "dependencies" | "dev_dependencies" => {
+                    let (name, constraint) = match item.split_once(':') {
+                        Some((name, constraint)) if !unquote(constraint).is_empty() => (name.trim(), unquote(constraint)),
+                        _ => return Err(fragment_error(path, index)),
+                    };
+                    let packages = if section == "dependencies" {
+                        &mut requirements.dependencies
+                    } else {
+                        &mut requirements.dev_dependencies
+                    };
+                    packages.insert(name.to_owned(), constraint.to_owned());
+                },
+                _ => return Err(fragment_error(path, index)),
+            }
+        }
+        Ok(requirements)
+    }
+
+    /// Adds `other`'s requirements, keeping ours where both name a package.
+    pub fn extend(&mut self, other: PubspecRequirements) {
+        for (name, constraint) in other.dependencies {
+            self.dependencies.entry(name).or_insert(constraint);
+        }
+        for (name, constraint) in other.dev_dependencies {
+            self.dev_dependencies.entry(name).or_insert(constraint);
+        }
+        for asset in other.assets {
+            if !self.assets.contains(&asset) {
+                self.assets.push(asset);
+            }
+        }
+    }
+
+    /// Fills in placeholders in asset paths.
+    pub fn render(mut self, template: &template::Template) -> Self {
+        self.assets = self.assets.into_iter()
+            .map(|asset| template.render(&asset).unwrap_or(asset))
+            .collect();
+        self
+    }
+
+    /// Adds whatever `pubspec` is missing, editing it line by line so its
+    /// formatting and comments stay as they are.
+    ///
+    /// Packages it already has are left alone, and reported as conflicts when
+    /// no version could satisfy both constraints.
+    pub fn merge_into(&self, pubspec: &str) -> MergeOutcome {
+        let mut lines: Vec<String> = pubspec.lines().map(str::to_owned).collect();
+        let mut outcome = MergeOutcome::default();
+
+        for (header, packages) in &[("dependencies", &self.dependencies), ("dev_dependencies", &self.dev_dependencies)] {
+            for (name, constraint) in packages.iter() {
+                let section = Section::find(&lines, header);
+                if let Some(existing) = section.as_ref().and_then(|section| section.value(&lines, name)) {
+                    if !compatible(&existing, constraint) {
+                        outcome.conflicts.push(Conflict {
+                            package: name.to_owned(), existing, required: constraint.to_owned()
+                        });
+                    }
+                    continue;
+                }
+                let line = format!("{}: {}", name, quote(constraint));
+                outcome.added.push(format!("{}: {}", header, line));
+                insert_child(&mut lines, section, header, line);
+            }
+        }
+
+        for asset in &self.assets {
+            let flutter = Section::find(&lines, "flutter");
+            let assets = flutter.as_ref().and_then(|flutter| flutter.child(&lines, "assets"));
+            let listed = assets.as_ref().is_some_and(|assets| {
+                lines[assets.start + 1..assets.end].iter().any(|line| unquote(strip_comment(line).trim().trim_start_matches("- ")) == asset)
+            });
+            if listed {
+                continue;
+            }
+            outcome.added.push(format!("flutter: assets: - {}", asset));
+            match (flutter, assets) {
+                (_, Some(assets)) => {
+                    let indent = assets.child_indent(&lines);
+                    lines.insert(assets.end, format!("{}- {}", indent, quote(asset)));
+                },
+                (Some(flutter), None) => {
+                    let indent = flutter.child_indent(&lines);
+                    lines.insert(flutter.end, format!("{}{}- {}", indent, indent, quote(asset)));
+                    lines.insert(flutter.end, format!("{}assets:", indent));
+                },
+                (None, None) => {
+                    if lines.last().is_some_and(|last| !last.trim().is_empty()) {
+                        lines.push(String::new());
+                    }
+                    lines.push("flutter:".to_owned());
+                    lines.push("  assets:".to_owned());
+                    lines.push(format!("    - {}", quote(asset)));
+                },
+            }
+        }
+
+        outcome.pubspec = lines.join("\n");
+        if pubspec.ends_with('\n') || pubspec.is_empty() {
+            outcome.pubspec.push('\n');
+        }
+        outcome
+    }
+}
+
+// A key and the indented lines under it.
+#[derive(Debug)]
+struct Section {
+    // The key's line
+    start: usize,
+    // Just past the last indented line, trailing blanks and comments aren't part of it
+    end: usize,
+    indent: usize,
+}
+
+impl Section {
+    // A top level key.
+    fn find(lines: &[String], key: &str) -> Option<Section> {
+        let start = lines.iter().position(|line| strip_comment(line).trim_end() == format!("{}:", key))?;
+        Some(Section::from_start(lines, start, 0))
+    }
+
+    fn from_start(lines: &[String], start: usize, indent: usize) -> Section {
+        let mut end = start + 1;
+        for (index, line) in lines.iter().enumerate().skip(start + 1) {
+            let content = strip_comment(line);
+            if content.trim().is_empty() {
+                continue;
+            }
+            if indent_of(content) <= indent {
+                break;
+            }
+            end = index + 1;
+        }
+        Section { start, end, indent }
+    }
+
+    fn child_indent(&self, lines: &[String]) -> String {
+        lines[self.start + 1..self.end].iter()
+            .map(|line| strip_comment(line))
+            .find(|content| !content.trim().is_empty())
+            .map(|content| " ".repeat(indent_of(content)))
+            .unwrap_or_else(|| " ".repeat(self.indent + 2))
+    }
+
+    // A key directly under this one.
+    fn child(&self, lines: &[String], key: &str) -> Option<Section> {
+        let indent = self.child_indent(lines).len();
+        (self.start + 1..self.end)
+            .find(|&index| {
+                let content = strip_comment(&lines[index]);
+                indent_of(content) == indent && content.trim_end().trim_start() == format!("{}:", key)
+            })
+            .map(|start| Section::from_start(lines, start, indent))
+    }
+
+    // The scalar under a child key, "" for a nested map (path, git or sdk dependencies).
+    fn value(&self, lines: &[String], key: &str) -> Option<String> {
+        let indent = self.child_indent(lines).len();
+        lines[self.start + 1..self.end].iter()
+            .map(|line| strip_comment(line))
+            .filter(|content| indent_of(content) == indent)
+            .find_map(|content| {
+                let (name, value) = content.trim().split_once(':')?;
+                if name.trim() == key { Some(unquote(value).to_owned()) } else { None }
+            })
+    }
+}
+
+fn insert_child(lines: &mut Vec<String>, section: Option<Section>, header: &str, line: String) {
+    match section {
+        Some(section) => {
+            let indent = section.child_indent(lines);
+            lines.insert(section.end, format!("{}{}", indent, line));
+        },
+        None => {
+            if lines.last().is_some_and(|last| !last.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(format!("{}:", header));
+            lines.push(format!("  {}", line));
+        },
+    }
+}
+
+/// Whether some version satisfies both Dart constraints.
+///
+/// Only the versions named in either constraint are tried, which is enough
+/// for the usual `^1.2.0`, `1.2.0` and `>=1.0.0 <2.0.0` forms. Constraints
+/// that aren't version constraints (path or git dependencies) or don't parse
+/// only count as compatible when they are the same.
+pub fn compatible(existing: &str, required: &str) -> bool {
+    if existing.is_empty() || existing == required {
+        return true
+    }
+    let (existing_req, required_req) = match (version_req(existing), version_req(required)) {
+        (Some(existing_req), Some(required_req)) => (existing_req, required_req),
+        _ => return false,
+    };
+
+    let mut candidates = vec![Version::new(0, 0, 0)];
+    for comparator in existing_req.comparators.iter().chain(&required_req.comparators) {
+        let version = Version::new(comparator.major, comparator.minor.unwrap_or(0), comparator.patch.unwrap_or(0));
+        candidates.push(Version::new(version.major, version.minor, version.patch + 1));
+        candidates.push(version);
+    }
+    candidates.iter().any(|version| existing_req.matches(version) && required_req.matches(version))
+}
+
+// Dart writes ranges space separated and exact versions bare.
+fn version_req(constraint: &str) -> Option<VersionReq> {
+    if constraint == "any" {
+        return Some(VersionReq::STAR)
+    }
+    let comparators: Vec<String> = constraint.split_whitespace()
+        .map(|part| if part.starts_with(|c: char| c.is_ascii_digit()) { format!("={}", part) } else { part.to_owned() })
+        .collect();
+    VersionReq::parse(&comparators.join(", ")).ok()
+}
+
+// The line up to its comment, a "#" only starts one at the start of the line
+// or after whitespace, and never inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous: Option<char> = None;
+    for (index, c) in line.char_indices() {
+        // Quotes only open a scalar at its start, "it's" is plain text
+        let starts_scalar = previous.is_none_or(|previous| previous.is_whitespace() || "[{,".contains(previous));
+        match quote {
+            // Only double quoted scalars have escapes, '' in single quotes closes and reopens
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {},
+            None if (c == '\'' || c == '"') && starts_scalar => quote = Some(c),
+            None if c == '#' && previous.is_none_or(char::is_whitespace) => return &line[..index],
+            None => {},
+        }
+        previous = Some(c);
+    }
+    line
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim().trim_matches(|c| c == '\'' || c == '"')
+}
+
+// Constraints like ">=1.0.0 <2.0.0" aren't plain YAML scalars, and neither
+// are values with a " #" that would start a comment.
+fn quote(value: &str) -> String {
+    let plain = value.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '^' || c == '/' || c == '_');
+    if plain && !value.contains(" #") {
+        value.to_owned()
+    } else {
+        format!("'{}'", value)
+    }
+}
+
+fn fragment_error(path: &Path, index: usize) -> errors::CupeyError {
+    let message = format!(
+        "Couldn't read '{}' line {}: only version constraints under dependencies and dev_dependencies, and flutter: assets: are supported",
+        path.to_str().unwrap(), index + 1
+    );
+    errors::CupeyError::new(message, errors::ErrorKind::ManifestError)
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{errors, manifest, GeneralResult};
+
+
+/// A source folder registered under a short name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegistryEntry {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Named sources added with `cupey add`, so `cupey use <name>` doesn't need the full path.
+///
+/// Lives in `<cupey home>/registry.toml`.
+#[derive(Debug, Default)]
+pub struct Registry {
+    path: PathBuf,
+    pub entries: BTreeMap<String, RegistryEntry>,
+}
+
+// The file as written.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    sources: BTreeMap<String, RegistryEntry>,
+}
+
+impl Registry {
+    /// Loads the registry from cupey home, starting empty if there is none yet.
+    pub fn load() -> Result<Self, errors::CupeyError> {
+        Self::load_from(crate::cupey_home().join("registry.toml"))
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<Self, errors::CupeyError> {
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            let file: RegistryFile = toml::from_str(&fs::read_to_string(&path)?).map_err(|err| {
+                let message = format!("Couldn't read '{}': {}", path.to_str().unwrap(), err);
+                errors::CupeyError::new(message, errors::ErrorKind::RegistryError)
+            })?;
+            entries = file.sources;
+        }
+        Ok(Registry { path, entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Registers `source` as `name`, replacing whatever had that name.
+    ///
+    /// Without a description the source's manifest description is used, if it has one.
+    pub fn add(&mut self, name: &str, source: &Path, description: Option<String>) -> GeneralResult {
+        let path = source.canonicalize()?;
+        let description = match description {
+            Some(description) => Some(description),
+            None => manifest::Manifest::load(&path)?.and_then(|manifest| manifest.description),
+        };
+        self.entries.insert(name.to_owned(), RegistryEntry { path, description });
+        Ok(())
+    }
+
+    /// Looks up a registered source, the error lists what is registered.
+    pub fn get(&self, name: &str) -> Result<&RegistryEntry, errors::CupeyError> {
+        self.entries.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.entries.keys().map(String::as_str).collect();
+            let message = if known.is_empty() {
+                format!("No source named '{}', register one with cupey add <name> <path>", name)
+            } else {
+                format!("No source named '{}', registered sources are: {}", name, known.join(", "))
+            };
+            errors::CupeyError::new(message, errors::ErrorKind::RegistryError)
+        })
+    }
+
+    /// Turns `name` or `name/path/inside` into the folder or file it points at.
+    pub fn resolve(&self, name_and_path: &str) -> Result<PathBuf, errors::CupeyError> {
+        let mut parts = name_and_path.splitn(2, '/');
+        // splitn always yields at least one part
+        let entry = self.get(parts.next().unwrap())?;
+        let inner = match parts.next() {
+            Some(inner) if !inner.is_empty() => Path::new(inner),
+            _ => return Ok(entry.path.clone()),
+        };
+
+        if !inner.components().all(|component| matches!(component, Component::Normal(_))) {
+            let message = format!("'{}' should be a path inside the registered folder", inner.to_str().unwrap());
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::RegistryError))
+        }
+        let path = entry.path.join(inner);
+        if !path.exists() {
+            let message = format!("'{}' doesn't exist", path.to_str().unwrap());
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::RegistryError))
+        }
+        Ok(path)
+    }
+
+    /// `name_and_path` and everything its manifest depends on, directly or
+    /// through other dependencies, each once and dependencies first.
+    ///
+    /// A dependency is looked up inside the same registered folder first, so an
+    /// entry of `widgets` can depend on `theme` or `theme/colors.dart`, and
+    /// otherwise as a registered name with an optional path. Everything comes
+    /// back as `<name>` or `<name>/<path>`, the way `resolve` takes it.
+    pub fn with_dependencies(&self, name_and_path: &str) -> Result<Vec<String>, errors::CupeyError> {
+        self.resolve(name_and_path)?;
+        let mut resolved = Vec::new();
+        self.resolve_dependencies(name_and_path.trim_end_matches('/'), &mut Vec::new(), &mut resolved)?;
+        Ok(resolved)
+    }
+
+    // Depth first, `chain` holds the entries being resolved so a cycle can be reported.
+    fn resolve_dependencies(
+        &self, name_and_path: &str, chain: &mut Vec<String>, resolved: &mut Vec<String>
+    ) -> GeneralResult {
+        if resolved.iter().any(|done| done == name_and_path) {
+            return Ok(())
+        }
+        if let Some(start) = chain.iter().position(|pending| pending == name_and_path) {
+            let cycle: Vec<&str> = chain[start..].iter().map(String::as_str).chain(Some(name_and_path)).collect();
+            let message = format!("Dependency cycle: {}", cycle.join(" -> "));
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::RegistryError))
+        }
+
+        let dependencies = manifest::Manifest::load(&self.resolve(name_and_path)?)?
+            .map(|manifest| manifest.dependencies)
+            .unwrap_or_default();
+        // split always yields at least one part
+        let name = name_and_path.split('/').next().unwrap();
+
+        chain.push(name_and_path.to_owned());
+        for dependency in &dependencies {
+            let dependency = self.dependency_of(name, dependency).ok_or_else(|| {
+                let message = format!(
+                    "'{}' depends on '{}', which isn't in '{}' or registered", name_and_path, dependency, name
+                );
+                errors::CupeyError::new(message, errors::ErrorKind::RegistryError)
+            })?;
+            self.resolve_dependencies(&dependency, chain, resolved)?;
+        }
+        chain.pop();
+        resolved.push(name_and_path.to_owned());
+        Ok(())
+    }
+
+    // What `dependency`, declared by an entry of `name`, points at: a path inside
+    // `name` when there is one, otherwise a registered name with an optional path.
+    fn dependency_of(&self, name: &str, dependency: &str) -> Option<String> {
+        let dependency = dependency.trim_matches('/');
+        let inner = Path::new(dependency);
+        let is_relative = !dependency.is_empty()
+            && inner.components().all(|component| matches!(component, Component::Normal(_)));
+        if is_relative && self.entries.get(name).is_some_and(|entry| entry.path.join(inner).exists()) {
+            return Some(format!("{}/{}", name, dependency))
+        }
+        self.resolve(dependency).ok().map(|_| dependency.to_owned())
+    }
+
+    /// Registered name of the folder `path` is in, the innermost one if several are.
+    pub fn library_of(&self, path: &Path) -> Option<&str> {
+        let path = path.canonicalize().ok()?;
+        self.entries.iter()
+            .filter(|(_, entry)| path.starts_with(&entry.path))
+            .max_by_key(|(_, entry)| entry.path.components().count())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Writes the registry back to disk, replacing the old one in a single
+    /// rename so it is never left half written.
+    pub fn save(&self) -> GeneralResult {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = RegistryFile { sources: self.entries.clone() };
+        let text = toml::to_string(&file).map_err(|err| {
+            let message = format!("Couldn't write '{}': {}", self.path.to_str().unwrap(), err);
+            errors::CupeyError::new(message, errors::ErrorKind::RegistryError)
+        })?;
+        let temp_path = self.path.with_extension("toml.tmp");
+        fs::write(&temp_path, text)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Registered names are used on the command line, so they are kept simple.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+use std::path::PathBuf;
+
+
+/// Tally of what a run did, printed once at the end however many sources were copied.
+///
+/// Paths are destination paths.
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    pub copied: Vec<PathBuf>,
+    /// Left alone because the file already existed or was edited since the last sync.
+    pub skipped: Vec<PathBuf>,
+    /// Unchanged since the last incremental run.
+    pub up_to_date: Vec<PathBuf>,
+    /// Deleted by mirror.
+    pub removed: Vec<PathBuf>,
+}
+
+impl CopyReport {
+    pub fn record(&mut self, path: PathBuf, copied: bool) {
+        if copied {
+            self.copied.push(path);
+        } else {
+            self.skipped.push(path);
+        }
+    }
+}
+
+impl fmt::Display for CopyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{} copied, {} skipped", self.copied.len(), self.skipped.len())?;
+        if !self.up_to_date.is_empty() {
+            write!(f, ", {} up to date", self.up_to_date.len())?;
+        }
+        if !self.removed.is_empty() {
+            write!(f, ", {} removed", self.removed.len())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::registry::{Registry, RegistryEntry};
+use crate::{errors, manifest, template};
+
+
+// Larger files are only searched by name.
+const MAX_CONTENTS_LEN: u64 = 1024 * 1024;
+
+/// What part of a registered source matched the query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HitKind {
+    /// The registered name or its description.
+    Source,
+    /// A file or folder name.
+    Name,
+    /// A line of a file, 1 based.
+    Contents { line_number: usize, line: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    /// Registered name of the source the hit is in.
+    pub source: String,
+    /// Empty for `HitKind::Source` hits.
+    pub relative_path: PathBuf,
+    pub kind: HitKind,
+    pub score: u32,
+}
+
+impl SearchHit {
+    /// What to pass to `cupey use` to copy the hit.
+    pub fn use_path(&self) -> String {
+        if self.relative_path.as_os_str().is_empty() {
+            self.source.to_owned()
+        } else {
+            // Always forward slashes, so hits can be typed back in on any platform
+            format!("{}/{}", self.source, crate::forward_slashes(&self.relative_path))
+        }
+    }
+}
+
+/// Searches every registered source for `query`, best matches first.
+///
+/// Every word of the query has to show up (ignoring case) in the same name,
+/// description or line. Names beat descriptions, which beat contents, and
+/// files are only opened when `contents` is true. Files excluded by a
+/// source's manifest are left out.
+pub fn search(registry: &Registry, query: &str, contents: bool) -> Result<Vec<SearchHit>, errors::CupeyError> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    let mut hits = Vec::new();
+    if terms.is_empty() {
+        return Ok(hits)
+    }
+
+    for (name, entry) in &registry.entries {
+        search_source(name, entry, &terms, contents, &mut hits)?;
+    }
+
+    hits.sort_by(|a, b| {
+        (Reverse(a.score), &a.source, &a.relative_path).cmp(&(Reverse(b.score), &b.source, &b.relative_path))
+    });
+    Ok(hits)
+}
+
+fn search_source(
+    name: &str, entry: &RegistryEntry, terms: &[String], contents: bool, hits: &mut Vec<SearchHit>
+) -> Result<(), errors::CupeyError> {
+    // A registered folder may have been moved or deleted since
+    if !entry.path.is_dir() {
+        println!("Moving on, registered folder is missing: {:?}", entry.path);
+        return Ok(())
+    }
+    let source_manifest = manifest::Manifest::load(&entry.path)?;
+
+    let description = entry.description.clone()
+        .or_else(|| source_manifest.as_ref().and_then(|manifest| manifest.description.clone()));
+    let score = name_score(name, terms).max(
+        description.as_deref().map_or(0, |description| if matches_all(description, terms) { 40 } else { 0 })
+    );
+    if score > 0 {
+        hits.push(SearchHit { source: name.to_owned(), relative_path: PathBuf::new(), kind: HitKind::Source, score });
+    }
+
+    let mut search = SourceSearch { name, terms, contents, manifest: source_manifest.as_ref(), hits };
+    search.walk(&entry.path, Path::new(""))
+}
+
+struct SourceSearch<'s> {
+    name: &'s str,
+    terms: &'s [String],
+    contents: bool,
+    manifest: Option<&'s manifest::Manifest>,
+    hits: &'s mut Vec<SearchHit>,
+}
+
+impl SourceSearch<'_> {
+    fn walk(&mut self, dir: &Path, relative_dir: &Path) -> Result<(), errors::CupeyError> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            let relative_path = relative_dir.join(entry.file_name());
+            if self.manifest.is_some_and(|manifest| manifest.excludes(&relative_path)) {
+                continue;
+            }
+
+            let score = name_score(&entry.file_name().to_string_lossy(), self.terms);
+            if score > 0 {
+                self.hit(&relative_path, HitKind::Name, score);
+            }
+
+            if entry_path.is_dir() {
+                self.walk(&entry_path, &relative_path)?;
+            } else if self.contents && entry.metadata()?.len() <= MAX_CONTENTS_LEN {
+                self.search_contents(&entry_path, &relative_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Only the first matching line is reported, more matching lines rank the file higher.
+    fn search_contents(&mut self, path: &Path, relative_path: &Path) -> Result<(), errors::CupeyError> {
+        let bytes = fs::read(path)?;
+        if template::is_binary(&bytes) {
+            return Ok(())
+        }
+        let text = String::from_utf8_lossy(&bytes);
+
+        let mut matching = text.lines().enumerate().filter(|(_, line)| matches_all(line, self.terms));
+        if let Some((index, line)) = matching.next() {
+            let more = matching.count() as u32;
+            let kind = HitKind::Contents { line_number: index + 1, line: line.trim().to_owned() };
+            self.hit(relative_path, kind, 10 + more.min(9));
+        }
+        Ok(())
+    }
+
+    fn hit(&mut self, relative_path: &Path, kind: HitKind, score: u32) {
+        self.hits.push(SearchHit { source: self.name.to_owned(), relative_path: relative_path.to_owned(), kind, score });
+    }
+}
+
+// How well a name matches: whole name, then start of the name, then anywhere in it.
+fn name_score(name: &str, terms: &[String]) -> u32 {
+    if !matches_all(name, terms) {
+        return 0
+    }
+    let name = name.to_lowercase();
+    // "fancy button" is a whole name match for fancy_button.dart
+    let stem = name.split('.').next().unwrap_or("").replace(['_', '-'], " ");
+    let query = terms.join(" ");
+    if stem == query || name == query {
+        100
+    } else if name.starts_with(&terms[0]) {
+        70
+    } else {
+        50
+    }
+}
+
+fn matches_all(text: &str, terms: &[String]) -> bool {
+    let text = text.to_lowercase();
+    terms.iter().all(|term| text.contains(term.as_str()))
+}
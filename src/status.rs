@@ -0,0 +1,83 @@
+use std::fmt;
+use std::fs;
+
+use crate::lock::{LockEntry, LockFile};
+use crate::{errors, sync};
+
+
+/// How a copied file and its source compare to when it was copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Drift {
+    Unchanged,
+    /// The source changed, the copy didn't.
+    UpstreamChanged,
+    /// The copy was edited, the source didn't change.
+    LocallyEdited,
+    /// Both changed since the copy.
+    BothChanged,
+    /// An update left conflict markers in the copy that are still there.
+    Conflicted,
+    /// The copy was deleted.
+    Deleted,
+    /// The source file, archive or repository is gone.
+    SourceMissing,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let text = match self {
+            Drift::Unchanged => "unchanged",
+            Drift::UpstreamChanged => "upstream changed",
+            Drift::LocallyEdited => "edited",
+            Drift::BothChanged => "both changed",
+            Drift::Conflicted => "conflicted",
+            Drift::Deleted => "deleted",
+            Drift::SourceMissing => "source missing",
+        };
+        // Padding only works on the written str
+        f.pad(text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStatus {
+    pub entry: LockEntry,
+    pub drift: Drift,
+}
+
+/// Compares every file recorded in `lock` with its source, in lock order.
+pub fn status(lock: &LockFile) -> Result<Vec<FileStatus>, errors::CupeyError> {
+    lock.entries.values()
+        .map(|entry| Ok(FileStatus { entry: entry.clone(), drift: drift(lock, entry)? }))
+        .collect()
+}
+
+fn drift(lock: &LockFile, entry: &LockEntry) -> Result<Drift, errors::CupeyError> {
+    let destination = lock.full_path(entry);
+    if !destination.is_file() {
+        return Ok(Drift::Deleted)
+    }
+    let upstream = match entry.upstream_contents()? {
+        Some(upstream) => upstream,
+        None => return Ok(Drift::SourceMissing),
+    };
+
+    if entry.conflicted && has_conflict_markers(&fs::read(&destination)?) {
+        return Ok(Drift::Conflicted)
+    }
+
+    let upstream_changed = sync::hash_bytes(&upstream) != entry.source_hash;
+    let locally_edited = sync::hash_file(&destination)? != entry.hash;
+    Ok(match (upstream_changed, locally_edited) {
+        (false, false) => Drift::Unchanged,
+        (true, false) => Drift::UpstreamChanged,
+        (false, true) => Drift::LocallyEdited,
+        (true, true) => Drift::BothChanged,
+    })
+}
+
+// The lines diffy starts and ends a conflict with.
+fn has_conflict_markers(contents: &[u8]) -> bool {
+    String::from_utf8_lossy(contents).lines()
+        .any(|line| line.starts_with("<<<<<<< ") || line.starts_with(">>>>>>> "))
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+
+
+const STATE_HEADER: &str = "# cupey sync state";
+
+/// What an incremental run should do with a single source file.
+#[derive(Debug, PartialEq)]
+pub enum SyncAction {
+    /// Source is new or changed, destination is missing or untouched since the last sync.
+    Copy,
+    /// Source and destination both match what was recorded on the last sync.
+    UpToDate,
+    /// Destination was edited after the last sync.
+    LocallyModified,
+}
+
+/// What was recorded about a file the last time it was copied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncEntry {
+    pub hash: String,
+    pub source_mtime: u128,
+    pub source_len: u64,
+    pub dest_mtime: u128,
+}
+
+/// On-disk record of every file copied between one source and one destination.
+///
+/// Lives in `<cupey home>/state/`, one file per source/destination pair.
+#[derive(Debug)]
+pub struct SyncState {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, SyncEntry>,
+    seen: HashSet<PathBuf>,
+}
+
+impl SyncState {
+    /// Loads the state for `from_dir` -> `to_dir`, starting empty if there is none yet.
+    pub fn load(from_dir: &Path, to_dir: &Path) -> io::Result<Self> {
+        let mut path = crate::cupey_home();
+        path.push("state");
+        path.push(pair_key(from_dir, to_dir));
+        Self::load_from(path)
+    }
+
+    pub fn load_from(path: PathBuf) -> io::Result<Self> {
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            for line in fs::read_to_string(&path)?.lines() {
+                if line.starts_with('#') || line.is_empty() {
+                    continue;
+                }
+                let (relative_path, entry) = parse_line(line).ok_or_else(|| {
+                    let message = format!("Corrupt sync state line in {:?}: {}", path, line);
+                    io::Error::new(io::ErrorKind::InvalidData, message)
+                })?;
+                entries.insert(relative_path, entry);
+            }
+        }
+        Ok(SyncState { path, entries, seen: HashSet::new() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, relative_path: &Path) -> Option<&SyncEntry> {
+        self.entries.get(relative_path)
+    }
+
+    /// Compares the source and destination files against the recorded entry.
+    ///
+    /// Modification times and sizes are checked first, files are only hashed
+    /// when those differ.
+    pub fn check(&mut self, relative_path: &Path, source: &Path, dest: &Path) -> io::Result<SyncAction> {
+        self.seen.insert(relative_path.to_owned());
+
+        let entry = match self.entries.get(relative_path) {
+            Some(entry) if dest.exists() => entry,
+            _ => return Ok(SyncAction::Copy),
+        };
+
+        let dest_meta = fs::metadata(dest)?;
+        if mtime(&dest_meta) != entry.dest_mtime && hash_file(dest)? != entry.hash {
+            return Ok(SyncAction::LocallyModified);
+        }
+
+        let source_meta = fs::metadata(source)?;
+        if mtime(&source_meta) == entry.source_mtime && source_meta.len() == entry.source_len {
+            return Ok(SyncAction::UpToDate);
+        }
+        if hash_file(source)? == entry.hash {
+            return Ok(SyncAction::UpToDate);
+        }
+        Ok(SyncAction::Copy)
+    }
+
+    /// Records `source` as freshly copied to `dest`.
+    pub fn record(&mut self, relative_path: &Path, source: &Path, dest: &Path) -> io::Result<()> {
+        let source_meta = fs::metadata(source)?;
+        let entry = SyncEntry {
+            hash: hash_file(source)?,
+            source_mtime: mtime(&source_meta),
+            source_len: source_meta.len(),
+            dest_mtime: mtime(&fs::metadata(dest)?),
+        };
+        self.seen.insert(relative_path.to_owned());
+        self.entries.insert(relative_path.to_owned(), entry);
+        Ok(())
+    }
+
+    /// Writes the state back to disk, replacing the old one in a single rename
+    /// so it is never left half written.
+    ///
+    /// Entries for files that weren't seen during this run no longer exist in
+    /// the source and are dropped.
+    pub fn save(&mut self) -> io::Result<()> {
+        let seen = &self.seen;
+        self.entries.retain(|relative_path, _| seen.contains(relative_path));
+
+        let mut contents = String::from(STATE_HEADER);
+        contents.push('\n');
+        for (relative_path, entry) in &self.entries {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                entry.hash, entry.source_mtime, entry.source_len, entry.dest_mtime,
+                relative_path.to_string_lossy()
+            ));
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, SyncEntry)> {
+    let mut fields = line.splitn(5, '\t');
+    let entry = SyncEntry {
+        hash: fields.next()?.to_owned(),
+        source_mtime: fields.next()?.parse().ok()?,
+        source_len: fields.next()?.parse().ok()?,
+        dest_mtime: fields.next()?.parse().ok()?,
+    };
+    Some((PathBuf::from(fields.next()?), entry))
+}
+
+// State file name, derived from both absolute paths.
+fn pair_key(from_dir: &Path, to_dir: &Path) -> String {
+    let from_dir = from_dir.canonicalize().unwrap_or_else(|_| from_dir.to_owned());
+    let to_dir = to_dir.canonicalize().unwrap_or_else(|_| to_dir.to_owned());
+    let mut hasher = Sha256::new();
+    hasher.update(from_dir.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(to_dir.to_string_lossy().as_bytes());
+    to_hex(&hasher.finalize()[..16])
+}
+
+fn mtime(meta: &fs::Metadata) -> u128 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+}
+
+/// Hex encoded SHA-256 of `bytes`.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// Hex encoded SHA-256 of a file's contents.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+use crate::errors;
+
+
+// Only this much of a file is checked when deciding whether it is binary.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// Variables given with `--var`, substituted for `{{name}}` placeholders in
+/// file names, folder names and text file contents.
+///
+/// A placeholder can pass the value through filters, `{{name | snake_case}}`,
+/// applied left to right. Anything between `{{` and `}}` that isn't a name
+/// followed by filters is left as it is, so other uses of double braces in
+/// source files survive.
+#[derive(Debug, Clone, Default)]
+pub struct Template {
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Template {
+    pub fn new(vars: BTreeMap<String, String>) -> Self {
+        Template { vars }
+    }
+
+    /// Splits a `key=value` argument.
+    pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+        let mut parts = arg.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().ok_or_else(|| format!("Sorry '{}' should look like key=value", arg))?;
+        if !is_name(key) {
+            return Err(format!("Sorry '{}' isn't a valid variable name", key));
+        }
+        Ok((key.to_owned(), value.to_owned()))
+    }
+
+    /// Substitutes every placeholder in `text`, stopping at the first unknown variable or filter.
+    pub fn render(&self, text: &str) -> Result<String, RenderError> {
+        let mut rendered = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find("{{") {
+            let after_open = &rest[start + 2..];
+            let end = match after_open.find("}}") {
+                Some(end) => end,
+                None => break,
+            };
+            rendered.push_str(&rest[..start]);
+
+            let mut parts = after_open[..end].split('|').map(str::trim);
+            // split always yields at least one part
+            let name = parts.next().unwrap();
+            let filters: Vec<&str> = parts.collect();
+            if is_name(name) && filters.iter().all(|filter| is_name(filter)) {
+                let value = self.vars.get(name).ok_or_else(|| RenderError::UnknownVar(name.to_owned()))?;
+                let mut value = value.to_owned();
+                for filter in filters {
+                    value = apply_filter(filter, &value)
+                        .ok_or_else(|| RenderError::UnknownFilter(filter.to_owned()))?;
+                }
+                rendered.push_str(&value);
+            } else {
+                rendered.push_str(&rest[start..start + 2 + end + 2]);
+            }
+            rest = &after_open[end + 2..];
+        }
+
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+
+    /// Renders a single file or folder name, `path` is only used in error messages.
+    pub fn render_name(&self, name: &OsStr, path: &Path) -> Result<OsString, errors::CupeyError> {
+        // Names that aren't valid UTF-8 can't hold placeholders
+        let name_str = match name.to_str() {
+            Some(name_str) => name_str,
+            None => return Ok(name.to_owned()),
+        };
+
+        let rendered = self.render(name_str).map_err(|err| render_failed(err, path))?;
+        if rendered.is_empty() || rendered.contains('/') || rendered.contains('\\') {
+            let message = format!(
+                "'{}' renders to '{}', which isn't a valid file name", path.to_str().unwrap(), rendered
+            );
+            return Err(errors::CupeyError::new(message, errors::ErrorKind::TemplateError))
+        }
+        Ok(OsString::from(rendered))
+    }
+
+    /// Renders each part of a relative path.
+    pub fn render_path(&self, relative_path: &Path) -> Result<PathBuf, errors::CupeyError> {
+        let mut rendered = PathBuf::new();
+        for component in relative_path.components() {
+            match component {
+                Component::Normal(name) => rendered.push(self.render_name(name, relative_path)?),
+                other => rendered.push(other.as_os_str()),
+            }
+        }
+        Ok(rendered)
+    }
+
+    /// Renders a file's contents, binary files are returned untouched.
+    pub fn render_contents(&self, contents: Vec<u8>, path: &Path) -> Result<Vec<u8>, errors::CupeyError> {
+        if is_binary(&contents) {
+            return Ok(contents)
+        }
+        let text = match String::from_utf8(contents) {
+            Ok(text) => text,
+            Err(err) => return Ok(err.into_bytes()),
+        };
+        let rendered = self.render(&text).map_err(|err| render_failed(err, path))?;
+        Ok(rendered.into_bytes())
+    }
+}
+
+/// Why a placeholder couldn't be filled in.
+#[derive(Debug, PartialEq)]
+pub enum RenderError {
+    UnknownVar(String),
+    UnknownFilter(String),
+}
+
+fn render_failed(err: RenderError, path: &Path) -> errors::CupeyError {
+    let message = match err {
+        RenderError::UnknownVar(name) => format!(
+            "Unknown placeholder '{{{{{}}}}}' in '{}', pass it with --var {}=<value>",
+            name, path.to_str().unwrap(), name
+        ),
+        RenderError::UnknownFilter(filter) => format!(
+            "Unknown filter '{}' in '{}', expected one of: {}",
+            filter, path.to_str().unwrap(), FILTERS.join(", ")
+        ),
+    };
+    errors::CupeyError::new(message, errors::ErrorKind::TemplateError)
+}
+
+const FILTERS: [&str; 7] = [
+    "snake_case", "camel_case", "pascal_case", "kebab_case", "screaming_snake_case", "lower_case", "upper_case",
+];
+
+fn apply_filter(filter: &str, value: &str) -> Option<String> {
+    let words = words(value);
+    let converted = match filter {
+        "snake_case" => words.join("_"),
+        "kebab_case" => words.join("-"),
+        "screaming_snake_case" => words.join("_").to_uppercase(),
+        "pascal_case" => words.iter().map(|word| capitalize(word)).collect(),
+        "camel_case" => words.iter().enumerate()
+            .map(|(i, word)| if i == 0 { word.to_owned() } else { capitalize(word) })
+            .collect(),
+        "lower_case" => value.to_lowercase(),
+        "upper_case" => value.to_uppercase(),
+        _ => return None,
+    };
+    Some(converted)
+}
+
+/// Splits a name written in any of the usual cases into lowercase words:
+/// "MyHTTPWidget2", "my-http_widget2" and "my http widget2" all give
+/// ["my", "http", "widget2"].
+fn words(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let chars: Vec<char> = value.chars().collect();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(word.to_lowercase());
+                word.clear();
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            // "myWidget" splits before the W, "HTTPWidget" before the W too
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_is_lower) {
+                words.push(word.to_lowercase());
+                word.clear();
+            }
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word.to_lowercase());
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        },
+        _ => false,
+    }
+}
+
+/// Same check git uses: a NUL byte near the start means binary.
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(BINARY_CHECK_LEN).any(|byte| *byte == 0)
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use crate::{errors, template};
+
+
+/// Rewrites the contents of copied files, registered on a job with
+/// `CopyJob::add_transform`.
+///
+/// Each file's contents go through the job's transforms in the order they
+/// were added, each one getting what the one before it returned. The job's
+/// template, when it has one, always comes first.
+///
+/// ```no_run
+/// use std::io::Read;
+/// use std::path::Path;
+///
+/// #[derive(Debug)]
+/// struct Uppercase;
+///
+/// impl Cupey::transform::Transform for Uppercase {
+///     fn name(&self) -> &str {
+///         "uppercase"
+///     }
+///
+///     fn transform(&self, _path: &Path, input: &mut dyn Read) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///         let mut contents = Vec::new();
+///         input.read_to_end(&mut contents)?;
+///         Ok(contents.to_ascii_uppercase())
+///     }
+/// }
+/// ```
+pub trait Transform: fmt::Debug {
+    /// Shown when the transform fails.
+    fn name(&self) -> &str;
+
+    /// The new contents of the file at `relative_path`, read from `input`.
+    /// The path is relative to the folder the source is copied into, with
+    /// placeholders in it filled in.
+    fn transform(&self, relative_path: &Path, input: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+/// Runs `input` through `transforms` in order. With no transforms it is
+/// returned as it is.
+pub fn apply(
+    transforms: &[&dyn Transform], relative_path: &Path, input: &mut dyn Read
+) -> Result<Vec<u8>, errors::CupeyError> {
+    let mut contents = Vec::new();
+    input.read_to_end(&mut contents)?;
+    for transform in transforms {
+        contents = transform.transform(relative_path, &mut contents.as_slice())
+            .map_err(|err| failed(*transform, relative_path, err))?;
+    }
+    Ok(contents)
+}
+
+fn failed(transform: &dyn Transform, relative_path: &Path, err: Box<dyn Error>) -> errors::CupeyError {
+    // Built in transforms already say what went wrong
+    match err.downcast::<errors::CupeyError>() {
+        Ok(err) => *err,
+        Err(err) => {
+            let message = format!(
+                "Transform '{}' failed on '{}': {}", transform.name(), relative_path.to_str().unwrap(), err
+            );
+            errors::CupeyError::new(message, errors::ErrorKind::TransformError)
+        },
+    }
+}
+
+// Whole text file contents, None for binary files.
+fn read_text(input: &mut dyn Read) -> Result<Result<String, Vec<u8>>, Box<dyn Error>> {
+    let mut contents = Vec::new();
+    input.read_to_end(&mut contents)?;
+    if template::is_binary(&contents) {
+        return Ok(Err(contents))
+    }
+    Ok(String::from_utf8(contents).map_err(|err| err.into_bytes()))
+}
+
+/// Fills in placeholders, see `template::Template::render_contents`.
+impl Transform for template::Template {
+    fn name(&self) -> &str {
+        "template"
+    }
+
+    fn transform(&self, relative_path: &Path, input: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut contents = Vec::new();
+        input.read_to_end(&mut contents)?;
+        Ok(self.render_contents(contents, relative_path)?)
+    }
+}
+
+/// Makes every line of text files end the same way, binary files are left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEndings {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+}
+
+impl Transform for LineEndings {
+    fn name(&self) -> &str {
+        "line endings"
+    }
+
+    fn transform(&self, _relative_path: &Path, input: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+        let text = match read_text(input)? {
+            Ok(text) => text,
+            Err(contents) => return Ok(contents),
+        };
+        let text = text.replace("\r\n", "\n");
+        let text = match self {
+            LineEndings::Lf => text,
+            LineEndings::Crlf => text.replace('\n', "\r\n"),
+        };
+        Ok(text.into_bytes())
+    }
+}
+
+/// Puts `header` at the top of text files that don't already start with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LicenseHeader {
+    /// Written as is, comment markers included.
+    pub header: String,
+    /// Only files with these extensions get the header, all text files when empty.
+    pub extensions: Vec<String>,
+}
+
+impl LicenseHeader {
+    pub fn new(header: &str) -> Self {
+        LicenseHeader { header: header.to_owned(), extensions: Vec::new() }
+    }
+
+    fn applies_to(&self, relative_path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true
+        }
+        relative_path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| self.extensions.iter().any(|wanted| wanted.trim_start_matches('.') == extension))
+    }
+}
+
+impl Transform for LicenseHeader {
+    fn name(&self) -> &str {
+        "license header"
+    }
+
+    fn transform(&self, relative_path: &Path, input: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+        let text = match read_text(input)? {
+            Ok(text) => text,
+            Err(contents) => return Ok(contents),
+        };
+        if !self.applies_to(relative_path) || text.starts_with(&self.header) {
+            return Ok(text.into_bytes())
+        }
+        let mut with_header = self.header.clone();
+        if !with_header.ends_with('\n') {
+            with_header.push('\n');
+        }
+        with_header.push_str(&text);
+        Ok(with_header.into_bytes())
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::fs;
+
+use crate::lock::{self, LockFile};
+use crate::status::{self, Drift};
+use crate::{errors, template};
+
+
+/// What updating one copied file did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateOutcome {
+    /// The source didn't change.
+    UpToDate,
+    /// The copy wasn't edited, it now matches the source.
+    Updated,
+    /// Both changed, the source's changes were merged into the copy.
+    Merged,
+    /// Both changed the same lines, the copy now has conflict markers.
+    Conflicted,
+    /// Both changed the same lines and the copy was left as it was.
+    ConflictSkipped,
+    /// Nothing could be done, the reason why.
+    Skipped(String),
+}
+
+impl fmt::Display for UpdateOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            UpdateOutcome::UpToDate => write!(f, "up to date"),
+            UpdateOutcome::Updated => write!(f, "updated"),
+            UpdateOutcome::Merged => write!(f, "merged"),
+            UpdateOutcome::Conflicted => write!(f, "conflict, markers written"),
+            UpdateOutcome::ConflictSkipped => write!(f, "conflict, left alone"),
+            UpdateOutcome::Skipped(reason) => write!(f, "skipped, {}", reason),
+        }
+    }
+}
+
+/// Brings the files recorded in `lock` up to date with their sources,
+/// returning each one's path and outcome. `paths` limits the update to
+/// those files and folders, relative to the lock's folder.
+///
+/// Edited copies get a three-way merge, with the contents cupey wrote as the
+/// base. Templated copies are merged with the source filled in with the
+/// variables they were copied with. Where both sides changed the same lines conflict markers are
+/// written, or with `write_conflicts` false the copy is left alone. Call
+/// `LockFile::save` afterwards.
+pub fn update(
+    lock: &mut LockFile, paths: &[String], write_conflicts: bool
+) -> Result<Vec<(String, UpdateOutcome)>, errors::CupeyError> {
+    let mut outcomes = Vec::new();
+    for file_status in status::status(lock)? {
+        let path = file_status.entry.path.clone();
+        let selected = paths.is_empty() || paths.iter().any(|selected| {
+            let selected = selected.trim_end_matches('/');
+            path == selected || path.starts_with(&format!("{}/", selected))
+        });
+        if selected {
+            let outcome = update_file(lock, &file_status, write_conflicts)?;
+            outcomes.push((path, outcome));
+        }
+    }
+    Ok(outcomes)
+}
+
+fn update_file(
+    lock: &mut LockFile, file_status: &status::FileStatus, write_conflicts: bool
+) -> Result<UpdateOutcome, errors::CupeyError> {
+    let entry = &file_status.entry;
+    match file_status.drift {
+        Drift::Unchanged | Drift::LocallyEdited => return Ok(UpdateOutcome::UpToDate),
+        Drift::Deleted => return Ok(UpdateOutcome::Skipped("deleted here".to_owned())),
+        Drift::SourceMissing => return Ok(UpdateOutcome::Skipped("source is missing".to_owned())),
+        Drift::Conflicted => return Ok(UpdateOutcome::Skipped("conflict markers left, resolve them first".to_owned())),
+        Drift::UpstreamChanged | Drift::BothChanged => {},
+    }
+    // Transforms aren't kept, the source alone doesn't say what to write
+    if entry.transformed {
+        return Ok(UpdateOutcome::Skipped("run through transforms, copy it again".to_owned()))
+    }
+
+    let destination = lock.full_path(entry);
+    // Writing through a link would change the source itself
+    if fs::symlink_metadata(&destination)?.file_type().is_symlink() {
+        return Ok(UpdateOutcome::Skipped("links to its source".to_owned()))
+    }
+    // status just read it, it exists
+    let upstream = entry.upstream_contents()?.unwrap_or_default();
+    // Templated copies are merged with the new contents filled in the same way
+    let rendered = if entry.vars.is_empty() {
+        upstream.clone()
+    } else {
+        let template = template::Template::new(entry.vars.clone());
+        match template.render_contents(upstream.clone(), &destination) {
+            Ok(rendered) => rendered,
+            Err(_) => return Ok(UpdateOutcome::Skipped("the source now has placeholders the copy has no values for".to_owned())),
+        }
+    };
+    // Rewritten imports and hook changes are merged like edits
+    if file_status.drift == Drift::UpstreamChanged && entry.written_hash.is_none() {
+        fs::write(&destination, &rendered)?;
+        lock.record_update(&entry.path, &upstream, &rendered, false)?;
+        return Ok(UpdateOutcome::Updated)
+    }
+
+    let base = match lock::load_base(entry.base_hash())? {
+        Some(base) => base,
+        None => return Ok(UpdateOutcome::Skipped("the copied contents weren't kept on this machine".to_owned())),
+    };
+    let local = fs::read(&destination)?;
+    let texts = (as_text(&base), as_text(&local), as_text(&rendered));
+    let (base, local, upstream_text) = match texts {
+        (Some(base), Some(local), Some(upstream)) => (base, local, upstream),
+        _ => return Ok(UpdateOutcome::Skipped("not a text file".to_owned())),
+    };
+
+    let (merged, outcome) = match diffy::merge(base, local, upstream_text) {
+        Ok(merged) => (merged, UpdateOutcome::Merged),
+        Err(_) if !write_conflicts => return Ok(UpdateOutcome::ConflictSkipped),
+        Err(with_markers) => (with_markers, UpdateOutcome::Conflicted),
+    };
+    fs::write(&destination, merged)?;
+    lock.record_update(&entry.path, &upstream, &rendered, outcome == UpdateOutcome::Conflicted)?;
+    Ok(outcome)
+}
+
+fn as_text(contents: &[u8]) -> Option<&str> {
+    if template::is_binary(contents) {
+        return None
+    }
+    std::str::from_utf8(contents).ok()
+}
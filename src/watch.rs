@@ -0,0 +1,167 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::{copier, mirror, visit_dirs_with, CopyJob, GeneralResult};
+
+
+// How often the inotify queue is polled while waiting for events.
+const POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Delete destination files when their source is deleted.
+    pub mirror: bool,
+    /// Quiet period to wait for after the last event before syncing.
+    ///
+    /// Editors that save through temp files and renames fire a burst of
+    /// events, waiting for the burst to end means only the final file is copied.
+    pub debounce: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions { mirror: false, debounce: Duration::from_millis(200) }
+    }
+}
+
+/// Keeps a destination folder in step with changes made to a source folder.
+pub struct SourceWatcher {
+    from_dir: PathBuf,
+    to_dir: PathBuf,
+    options: WatchOptions,
+    inotify: Inotify,
+    // Watched folders, relative to from_dir (inotify watches aren't recursive).
+    watched_dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl SourceWatcher {
+    /// Starts watching `from_dir`, nothing is copied until `sync_changes` is called.
+    pub fn new(from_dir: &Path, to_dir: &Path, options: WatchOptions) -> io::Result<Self> {
+        let mut watcher = SourceWatcher {
+            from_dir: from_dir.to_owned(),
+            to_dir: to_dir.to_owned(),
+            options,
+            inotify: Inotify::init()?,
+            watched_dirs: HashMap::new(),
+        };
+        watcher.watch_tree(Path::new(""))?;
+        Ok(watcher)
+    }
+
+    /// Blocks forever, copying changes as they happen. Only stops when the
+    /// source can't be watched any more.
+    pub fn run(&mut self) -> GeneralResult {
+        println!("Watching {:?} for changes, press Ctrl+C to stop", &self.from_dir);
+        loop {
+            self.sync_changes(None)?;
+        }
+    }
+
+    /// Waits for a burst of changes (up to `timeout` if given) and applies it
+    /// to the destination.
+    ///
+    /// Returns the relative paths that changed in the source. A path that
+    /// can't be synced, like a temp file gone by the time it is copied, is
+    /// reported and skipped, only failing to read the changes is an error.
+    pub fn sync_changes(&mut self, timeout: Option<Duration>) -> Result<Vec<PathBuf>, crate::errors::CupeyError> {
+        let changed = self.wait_for_changes(timeout)?;
+        for relative_path in &changed {
+            if let Err(err) = self.sync_path(relative_path) {
+                println!("Moving on, couldn't sync {:?}: {}", relative_path, err);
+            }
+        }
+        Ok(changed.into_iter().collect())
+    }
+
+    fn wait_for_changes(&mut self, timeout: Option<Duration>) -> io::Result<BTreeSet<PathBuf>> {
+        let started = Instant::now();
+        let mut last_event: Option<Instant> = None;
+        let mut changed = BTreeSet::new();
+        let mut buffer = [0; 4096];
+
+        loop {
+            let read_any = self.read_events(&mut buffer, &mut changed)?;
+            if read_any {
+                last_event = Some(Instant::now());
+                continue;
+            }
+
+            match last_event {
+                Some(at) if at.elapsed() >= self.options.debounce => return Ok(changed),
+                None if timeout.is_some_and(|timeout| started.elapsed() >= timeout) => {
+                    return Ok(changed)
+                },
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    // Drains whatever is queued right now, false if there was nothing.
+    fn read_events(&mut self, buffer: &mut [u8], changed: &mut BTreeSet<PathBuf>) -> io::Result<bool> {
+        let events = match self.inotify.read_events(buffer) {
+            Ok(events) => events,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        for event in events {
+            if event.mask.contains(EventMask::IGNORED) {
+                // Watched folder is gone
+                self.watched_dirs.remove(&event.wd);
+                continue;
+            }
+            let (dir, name) = match (self.watched_dirs.get(&event.wd), event.name) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => continue,
+            };
+            changed.insert(dir.join(name));
+        }
+        Ok(true)
+    }
+
+    // Brings a single changed path in the destination in line with the source.
+    fn sync_path(&mut self, relative_path: &Path) -> GeneralResult {
+        let source = self.from_dir.join(relative_path);
+        let destination = self.to_dir.join(relative_path);
+
+        if source.is_dir() {
+            // Files may have landed in a new folder before it was watched,
+            // copy whatever is already in there.
+            self.watch_tree(relative_path)?;
+            fs::create_dir_all(&destination)?;
+            if fs::read_dir(&source)?.next().is_some() {
+                visit_dirs_with(&source, &destination, &mut CopyJob::new(true))?;
+            }
+        } else if source.is_file() {
+            let mut destination_dir = destination.parent().unwrap().to_owned();
+            fs::create_dir_all(&destination_dir)?;
+            copier(&source, &mut destination_dir, true)?;
+        } else if self.options.mirror && fs::symlink_metadata(&destination).is_ok() {
+            mirror::remove_paths(&[destination], None)?;
+        }
+        Ok(())
+    }
+
+    // Adds watches for a folder and everything under it.
+    fn watch_tree(&mut self, relative_dir: &Path) -> io::Result<()> {
+        let dir = self.from_dir.join(relative_dir);
+        let mask = WatchMask::CREATE | WatchMask::CLOSE_WRITE | WatchMask::MODIFY
+            | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE;
+        let descriptor = self.inotify.watches().add(&dir, mask)?;
+        self.watched_dirs.insert(descriptor, relative_dir.to_owned());
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.watch_tree(&relative_dir.join(entry.file_name()))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,20 @@
+// Each test binary uses a different subset of these helpers.
+#![allow(dead_code)]
+
 use std::path;
 use std::fs;
 use std::env;
 use std::io::{Read, Write};
 
-use Cupey;
-
-/// Test utils functions will Panic on errors, they are intended to be simple
-/// and not propagate errors.
+// Test utils functions will Panic on errors, they are intended to be simple
+// and not propagate errors.
 
 
 pub const TEST_FOLDER_NAME: &str = "cupey_test_folder";
 
+// Written next to copied files, sizes and counts compare copies only.
+const LOCK_FILE_NAME: &str = "cupey.lock";
+
 
 pub fn clean_up(dir: &path::Path) {
     if dir.exists() {
@@ -22,6 +26,7 @@ pub fn clean_up(dir: &path::Path) {
 }
 
 pub fn cupey_test_folder_path() -> std::path::PathBuf {
+    use_test_cupey_home();
     let mut cupey_test_folder_path = env::current_dir().unwrap();
     cupey_test_folder_path.push(TEST_FOLDER_NAME);
     cupey_test_folder_path
@@ -30,14 +35,15 @@ pub fn cupey_test_folder_path() -> std::path::PathBuf {
 pub fn folder_size(dir: &path::Path) -> u64 {
 
     fn recurse(vec_: &mut Vec<u64>, dir: &path::Path) -> u64 {
-        for entry in fs::read_dir(dir).unwrap() {
-            if let Ok(dir_entry) = entry {
-                if dir_entry.path().is_dir() {
-                    recurse(vec_, &dir_entry.path());
-                } else {
-                    let entry_size = dir_entry.metadata().unwrap().len();
-                    vec_.push(entry_size);
-                }
+        for dir_entry in fs::read_dir(dir).unwrap().flatten() {
+            if dir_entry.file_name() == LOCK_FILE_NAME {
+                continue;
+            }
+            if dir_entry.path().is_dir() {
+                recurse(vec_, &dir_entry.path());
+            } else {
+                let entry_size = dir_entry.metadata().unwrap().len();
+                vec_.push(entry_size);
             }
         }
         vec_.iter().sum()
@@ -58,13 +64,9 @@ pub fn folder_count(dir: &path::Path) -> u64 {
     match Cupey::empty_dir(dir) {
         true => 0,
         false => {
-            let mut count = 0;
-            for entry in fs::read_dir(dir).unwrap() {
-                if let Ok(_) = entry {
-                    count += 1;
-                }
-            }
-            count
+            fs::read_dir(dir).unwrap().flatten()
+                .filter(|dir_entry| dir_entry.file_name() != LOCK_FILE_NAME)
+                .count() as u64
         }
     }
 }
@@ -86,9 +88,9 @@ fn get_extension_from_filename(filename: &str) -> Option<&str> {
 pub fn create_txt_file(text: &str, dest_file_path: &path::Path, overwrite: Option<bool>) {
 
     // Check if file is a text file
-    if let Some(file_extention) = get_extension_from_filename(&dest_file_path.to_str().unwrap()) {
+    if let Some(file_extention) = get_extension_from_filename(dest_file_path.to_str().unwrap()) {
         if file_extention == "txt" {
-            write_from_string(&text, dest_file_path, overwrite);
+            write_from_string(text, dest_file_path, overwrite);
         }
     }
 }
@@ -118,7 +120,6 @@ fn write_from_string(string: &str, dest_file_path: &path::Path, overwrite: Optio
         // Create new file, append if exists
         Some(false) => {
             dest_file = fs::OpenOptions::new()
-                .write(true)
                 .create(true)
                 .append(true)
                 .open(dest_file_path)
@@ -126,7 +127,7 @@ fn write_from_string(string: &str, dest_file_path: &path::Path, overwrite: Optio
         }
     }
 
-    dest_file.write(string.as_bytes()).unwrap();
+    dest_file.write_all(string.as_bytes()).unwrap();
     
 }
 
@@ -141,4 +142,90 @@ pub fn read_to_string(file_path: &path::Path) -> String {
     file_to_read.read_to_string(&mut string_buffer).unwrap();
 
     string_buffer
-}
\ No newline at end of file
+}
+/// Creates an empty folder with the given name in the current dir,
+/// removing any leftovers from a previous run first.
+pub fn fresh_dir(name: &str) -> path::PathBuf {
+    // Copies keep what they wrote in cupey home
+    use_test_cupey_home();
+    let mut dir = env::current_dir().unwrap();
+    dir.push(name);
+    clean_up(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Creates a small source tree of its own so tests don't race on the shared
+/// test folder:
+///
+/// name/
+///     a.txt
+///     b.txt
+///     inner/c.txt
+pub fn fixture_dir(name: &str) -> path::PathBuf {
+    let dir = fresh_dir(name);
+    fs::create_dir_all(dir.join("inner")).unwrap();
+    create_txt_file("Some stuff in a", &dir.join("a.txt"), None);
+    create_txt_file("Some other stuff in b", &dir.join("b.txt"), None);
+    create_txt_file("Nested stuff in c", &dir.join("inner").join("c.txt"), None);
+    dir
+}
+
+/// Points cupey at a home folder inside the current dir so tests never touch
+/// the real `~/.cupey`.
+pub fn use_test_cupey_home() -> path::PathBuf {
+    let mut home = env::current_dir().unwrap();
+    home.push("cupey_test_home");
+    env::set_var("CUPEY_HOME", &home);
+    home
+}
+
+/// Packs the fixture tree (see `fixture_dir`) into `<name>.tar.gz` and
+/// `<name>.zip` in the current dir.
+pub fn fixture_archives(name: &str) -> (path::PathBuf, path::PathBuf) {
+    let source_dir = fixture_dir(name);
+    let tar_gz_path = source_dir.with_extension("tar.gz");
+    let zip_path = source_dir.with_extension("zip");
+
+    let encoder = flate2::write::GzEncoder::new(
+        fs::File::create(&tar_gz_path).unwrap(), flate2::Compression::default()
+    );
+    let mut tar_builder = tar::Builder::new(encoder);
+    tar_builder.append_dir_all(".", &source_dir).unwrap();
+    tar_builder.into_inner().unwrap().finish().unwrap();
+
+    let mut zip_writer = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+    let options = zip::write::SimpleFileOptions::default();
+    for relative_path in &["a.txt", "b.txt", "inner/c.txt"] {
+        zip_writer.start_file(*relative_path, options).unwrap();
+        zip_writer.write_all(read_to_string(&source_dir.join(relative_path)).as_bytes()).unwrap();
+    }
+    zip_writer.finish().unwrap();
+
+    clean_up(&source_dir);
+    (tar_gz_path, zip_path)
+}
+
+/// Turns the fixture tree (see `fixture_dir`) into a git repository with a
+/// commit tagged "v1", then commits a change to "inner/c.txt" on top.
+pub fn fixture_git_repo(name: &str) -> path::PathBuf {
+    let repo_dir = fixture_dir(name);
+    let repo = git2::Repository::init(&repo_dir).unwrap();
+    let signature = git2::Signature::now("cupey", "cupey@example.com").unwrap();
+
+    let commit_all = |message: &str| {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents).unwrap()
+    };
+
+    let first = commit_all("First");
+    repo.tag_lightweight("v1", &repo.find_object(first, None).unwrap(), false).unwrap();
+    create_txt_file("Changed c", &repo_dir.join("inner").join("c.txt"), Some(true));
+    commit_all("Second");
+    repo_dir
+}
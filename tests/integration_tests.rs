@@ -1,5 +1,4 @@
 
-use Cupey;
 use std::env;
 use std::fs;
 
@@ -20,7 +19,7 @@ fn cupey_works() {
     to_dir.push("to_dir");
 
     // Value will be moved eventually so create a copy
-    let mut to_dir_copy = to_dir.to_owned();
+    let to_dir_copy = to_dir.to_owned();
     cupey_inst.current_dir = to_dir;    // to_dir moved here
     
     // Just incase this dir already exists, remove it.
@@ -82,4 +81,947 @@ fn cupey_works() {
 // #[test]
 // fn cupey_overwrite() {
 
-// }
\ No newline at end of file
+// }
+
+#[test]
+fn cupey_mirror_works() {
+    let from_dir = common::fixture_dir("from_dir_mirror");
+    let to_dir = common::fresh_dir("to_dir_mirror");
+    // The rest of the project the folder's contents are copied into
+    fs::create_dir_all(to_dir.join(".git")).unwrap();
+    fs::create_dir_all(to_dir.join("lib")).unwrap();
+    fs::write(to_dir.join("pubspec.yaml"), "name: my_app\n").unwrap();
+    fs::write(to_dir.join(".cupey.toml"), "").unwrap();
+    let stale_file = to_dir.join("inner").join("stale.txt");
+    fs::create_dir_all(to_dir.join("inner")).unwrap();
+    common::create_txt_file("stale", &stale_file, None);
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--mirror", "--yes"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let stale_removed = !stale_file.exists();
+    let to_dir_count = common::folder_count(&to_dir);
+
+    // With --include-root the copy's own folder is mirrored from the top
+    let root_stale_file = to_dir.join("from_dir_mirror").join("stale.txt");
+    fs::create_dir_all(to_dir.join("from_dir_mirror").join(".git")).unwrap();
+    common::create_txt_file("stale", &root_stale_file, None);
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--include-root", "--mirror", "--yes"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let root_stale_removed = !root_stale_file.exists();
+    let root_count = common::folder_count(&to_dir.join("from_dir_mirror"));
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(stale_removed);
+    assert_eq!(7, to_dir_count);
+    assert!(root_stale_removed);
+    assert_eq!(4, root_count);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn cupey_mirror_dry_run_and_rollback_work() {
+    let from_dir = common::fixture_dir("from_dir_mirror_rollback");
+    let to_dir = common::fresh_dir("to_dir_mirror_rollback");
+    fs::create_dir_all(to_dir.join("inner").join("stale_folder")).unwrap();
+    fs::write(to_dir.join("inner").join("stale.txt"), "Stale stuff").unwrap();
+    fs::write(to_dir.join("inner").join("stale_folder").join("d.txt"), "Stale stuff in d").unwrap();
+
+    // A dry run only lists what would go
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--mirror", "--dry-run"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let dry_run_kept = to_dir.join("inner").join("stale.txt").exists();
+    let dry_run_copied = to_dir.join("a.txt").exists();
+
+    // What mirror deleted comes back when a hook after it fails
+    fs::write(from_dir.join(".cupey.toml"), "[hooks]\npost = [\"false\"]\n").unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--mirror", "--rollback", "--yes"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let hook_result = cupey_inst.copy_files();
+
+    let stale_string = common::read_to_string(&to_dir.join("inner").join("stale.txt"));
+    let nested_string = common::read_to_string(&to_dir.join("inner").join("stale_folder").join("d.txt"));
+    let a_removed = !to_dir.join("a.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(dry_run_kept);
+    assert!(!dry_run_copied);
+    assert!(hook_result.is_err());
+    assert_eq!("Stale stuff", stale_string);
+    assert_eq!("Stale stuff in d", nested_string);
+    assert!(a_removed);
+}
+
+
+#[test]
+fn cupey_incremental_works() {
+    common::use_test_cupey_home();
+    let from_dir = common::fixture_dir("from_dir_incremental");
+    let to_dir = common::fresh_dir("to_dir_incremental");
+    let args = ["cupey", "--from", from_dir.to_str().unwrap(), "--incremental"];
+
+    let mut cupey_inst = Cupey::Cupey::new_from(args.iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    // Change a source file and edit a different copy in the destination
+    common::create_txt_file("New b", &from_dir.join("b.txt"), Some(true));
+    common::create_txt_file("Local a", &to_dir.join("a.txt"), Some(true));
+
+    let mut cupey_inst = Cupey::Cupey::new_from(args.iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    let b_string = common::read_to_string(&to_dir.join("b.txt"));
+    let c_string = common::read_to_string(&to_dir.join("inner").join("c.txt"));
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    // Changed source copied without --overwrite, local edit kept
+    assert_eq!("New b", b_string);
+    assert_eq!("Local a", a_string);
+    assert_eq!("Nested stuff in c", c_string);
+}
+
+
+#[test]
+fn cupey_move_works() {
+    let from_dir = common::fixture_dir("from_dir_move");
+    let to_dir = common::fresh_dir("to_dir_move");
+    // Conflicting file, should stay in the source without --overwrite
+    common::create_txt_file("Existing b", &to_dir.join("b.txt"), None);
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--move"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let a_moved = !from_dir.join("a.txt").exists() && to_dir.join("a.txt").exists();
+    let inner_moved = !from_dir.join("inner").exists() && to_dir.join("inner").join("c.txt").exists();
+    let b_string = common::read_to_string(&to_dir.join("b.txt"));
+    let b_kept = from_dir.join("b.txt").exists();
+    // Their sources are gone, status would only call them missing
+    let lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(a_moved);
+    assert!(inner_moved);
+    assert!(b_kept);
+    assert_eq!("Existing b", b_string);
+    assert!(lock.entries.is_empty());
+}
+
+
+#[cfg(unix)]
+#[test]
+fn cupey_link_works() {
+    let from_dir = common::fixture_dir("from_dir_link");
+    let to_dir = common::fresh_dir("to_dir_link");
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--link", "relative-symlink"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let c_link = to_dir.join("inner").join("c.txt");
+    let c_target = fs::read_link(&c_link).unwrap();
+    let c_string = common::read_to_string(&c_link);
+
+    // Links follow source edits
+    common::create_txt_file("Edited a", &from_dir.join("a.txt"), Some(true));
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    // An update would write through the links into the source
+    let lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(c_target.is_relative());
+    assert_eq!("Nested stuff in c", c_string);
+    assert_eq!("Edited a", a_string);
+    assert!(lock.entries.is_empty());
+}
+
+
+#[test]
+fn cupey_include_root_works() {
+    let from_dir = common::fixture_dir("from_dir_include_root");
+    let to_dir = common::fresh_dir("to_dir_include_root");
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--include-root"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let root_count = common::folder_count(&to_dir);
+    let copied_root = to_dir.join("from_dir_include_root");
+    let copied_count = common::folder_count(&copied_root);
+    let c_copied = copied_root.join("inner").join("c.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(1, root_count);
+    assert_eq!(3, copied_count);
+    assert!(c_copied);
+}
+
+
+#[test]
+fn cupey_multiple_sources_work() {
+    let from_dir = common::fixture_dir("from_dir_multiple");
+    let other_dir = common::fixture_dir("other_dir_multiple");
+    fs::rename(other_dir.join("a.txt"), other_dir.join("d.txt")).unwrap();
+    let to_dir = common::fresh_dir("to_dir_multiple");
+    fs::create_dir_all(to_dir.join("inner")).unwrap();
+    common::create_txt_file("stale", &to_dir.join("inner").join("stale.txt"), None);
+
+    // A folder and a single file from somewhere else, mirrored together
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(),
+        "-f", other_dir.join("d.txt").to_str().unwrap(), "--mirror", "--yes"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let to_dir_count = common::folder_count(&to_dir);
+    let d_copied = to_dir.join("d.txt").exists();
+    let stale_removed = !to_dir.join("inner").join("stale.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&other_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(4, to_dir_count);
+    assert!(d_copied);
+    assert!(stale_removed);
+}
+
+
+#[test]
+fn cupey_archive_sources_work() {
+    let (tar_gz_path, zip_path) = common::fixture_archives("from_archive");
+    let to_dir = common::fresh_dir("to_dir_archive");
+
+    // The tar.gz into its own folder, the zip straight into to_dir
+    for args in &[
+        ["cupey", "--from", tar_gz_path.to_str().unwrap(), "--include-root"],
+        ["cupey", "--from", zip_path.to_str().unwrap(), "--overwrite"],
+    ] {
+        let mut cupey_inst = Cupey::Cupey::new_from(args.iter()).unwrap();
+        cupey_inst.current_dir = to_dir.clone();
+        cupey_inst.copy_files().unwrap();
+    }
+
+    let root_count = common::folder_count(&to_dir);
+    let tar_c_string = common::read_to_string(&to_dir.join("from_archive").join("inner").join("c.txt"));
+    let zip_c_string = common::read_to_string(&to_dir.join("inner").join("c.txt"));
+    let zip_a_string = common::read_to_string(&to_dir.join("a.txt"));
+    common::clean_up(&tar_gz_path);
+    common::clean_up(&zip_path);
+    common::clean_up(&to_dir);
+
+    assert_eq!(4, root_count);
+    assert_eq!("Nested stuff in c", tar_c_string);
+    assert_eq!("Nested stuff in c", zip_c_string);
+    assert_eq!("Some stuff in a", zip_a_string);
+}
+
+
+#[test]
+fn cupey_to_archive_works() {
+    let from_dir = common::fixture_dir("from_dir_to_archive");
+    let to_dir = common::fresh_dir("to_dir_to_archive");
+
+    for archive_name in &["out.tar.gz", "out.zip"] {
+        let archive_path = to_dir.join(archive_name);
+        let mut cupey_inst = Cupey::Cupey::new_from([
+            "cupey", "--from", from_dir.to_str().unwrap(), "--include-root",
+            "--to-archive", archive_path.to_str().unwrap(), "--preserve-metadata"
+        ].iter()).unwrap();
+        cupey_inst.current_dir = to_dir.clone();
+        cupey_inst.copy_files().unwrap();
+
+        // Unpack it again with cupey
+        let unpacked_dir = to_dir.join(format!("unpacked_{}", archive_name));
+        let mut cupey_inst = Cupey::Cupey::new_from([
+            "cupey", "--from", archive_path.to_str().unwrap()
+        ].iter()).unwrap();
+        cupey_inst.current_dir = unpacked_dir;
+        cupey_inst.copy_files().unwrap();
+    }
+
+    // Nothing but the archives and what was unpacked from them
+    let to_dir_count = common::folder_count(&to_dir);
+    let tar_c_string = common::read_to_string(
+        &to_dir.join("unpacked_out.tar.gz").join("from_dir_to_archive").join("inner").join("c.txt")
+    );
+    let zip_a_string = common::read_to_string(
+        &to_dir.join("unpacked_out.zip").join("from_dir_to_archive").join("a.txt")
+    );
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(4, to_dir_count);
+    assert_eq!("Nested stuff in c", tar_c_string);
+    assert_eq!("Some stuff in a", zip_a_string);
+}
+
+
+#[test]
+fn cupey_from_git_works() {
+    let repo_dir = common::fixture_git_repo("from_git_repo");
+    // Uncommitted edits must not show up
+    common::create_txt_file("Uncommitted c", &repo_dir.join("inner").join("c.txt"), Some(true));
+    let to_dir = common::fresh_dir("to_dir_git");
+
+    for (rev, folder_name) in &[("v1", "old"), ("HEAD", "new")] {
+        let mut cupey_inst = Cupey::Cupey::new_from([
+            "cupey", "--from-git", repo_dir.to_str().unwrap(), "--rev", rev, "--subdir", "inner"
+        ].iter()).unwrap();
+        cupey_inst.current_dir = to_dir.join(folder_name);
+        cupey_inst.copy_files().unwrap();
+    }
+
+    let old_count = common::folder_count(&to_dir.join("old"));
+    let old_c_string = common::read_to_string(&to_dir.join("old").join("c.txt"));
+    let new_c_string = common::read_to_string(&to_dir.join("new").join("c.txt"));
+    common::clean_up(&repo_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(1, old_count);
+    assert_eq!("Nested stuff in c", old_c_string);
+    assert_eq!("Changed c", new_c_string);
+}
+
+
+#[test]
+fn cupey_var_works() {
+    let from_dir = common::fresh_dir("from_dir_var");
+    fs::create_dir_all(from_dir.join("{{name}}_widget")).unwrap();
+    common::create_txt_file(
+        "class {{class_name}} {}", &from_dir.join("{{name}}_widget").join("{{name}}.txt"), None
+    );
+    let to_dir = common::fresh_dir("to_dir_var");
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--var", "name=button", "--var", "class_name=Button"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let text_string = common::read_to_string(&to_dir.join("button_widget").join("button.txt"));
+
+    // Missing a variable
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--var", "name=button", "-o"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let missing_result = cupey_inst.copy_files();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!("class Button {}", text_string);
+    assert!(missing_result.is_err());
+}
+
+
+#[test]
+fn cupey_manifest_works() {
+    let from_dir = common::fresh_dir("from_dir_manifest");
+    fs::create_dir_all(from_dir.join("assets")).unwrap();
+    fs::write(from_dir.join(".cupey.toml"), r#"
+        exclude = ["*.log"]
+        verbatim = ["assets/**"]
+        messages = ["{{name}} is ready"]
+
+        [variables.name]
+        pattern = "[a-z_]+"
+
+        [variables.color]
+        default = "blue"
+    "#).unwrap();
+    common::create_txt_file("{{name}} is {{color}}", &from_dir.join("{{name}}.txt"), None);
+    common::create_txt_file("{{name}} stays", &from_dir.join("assets").join("raw.txt"), None);
+    fs::write(from_dir.join("debug.log"), "Not copied").unwrap();
+    let to_dir = common::fresh_dir("to_dir_manifest");
+
+    // name has no default and can't be asked for
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--no-input"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let missing_result = cupey_inst.copy_files();
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--no-input", "--var", "name=button"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let to_dir_count = common::folder_count(&to_dir);
+    let button_string = common::read_to_string(&to_dir.join("button.txt"));
+    let raw_string = common::read_to_string(&to_dir.join("assets").join("raw.txt"));
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(missing_result.is_err());
+    // button.txt and assets, no manifest or log
+    assert_eq!(2, to_dir_count);
+    assert_eq!("button is blue", button_string);
+    assert_eq!("{{name}} stays", raw_string);
+}
+
+
+#[test]
+fn cupey_registry_works() {
+    common::use_test_cupey_home();
+    let from_dir = common::fixture_dir("from_dir_registry");
+    let to_dir = common::fresh_dir("to_dir_registry");
+
+    let cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "add", "registry_fixture", from_dir.to_str().unwrap(), "--description", "Test fixture"
+    ].iter()).unwrap();
+    cupey_inst.run().unwrap();
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "use", "registry_fixture"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.run().unwrap();
+
+    let cupey_inst = Cupey::Cupey::new_from(["cupey", "use", "not_registered"].iter()).unwrap();
+    let missing_result = cupey_inst.run();
+
+    // An entry depending on another entry of the same library, in the same test
+    // as the other registry changes since they all share cupey home
+    let library_dir = common::fresh_dir("from_dir_registry_library");
+    let library_to_dir = common::fresh_dir("to_dir_registry_library");
+    fs::create_dir_all(library_dir.join("fancy_button")).unwrap();
+    fs::create_dir_all(library_dir.join("theme")).unwrap();
+    fs::write(library_dir.join("fancy_button").join(".cupey.toml"), "dependencies = [\"theme\"]\n").unwrap();
+    fs::write(library_dir.join("fancy_button").join("button.dart"), "class FancyButton {}\n").unwrap();
+    fs::write(library_dir.join("theme").join("colors.dart"), "const int primary = 1;\n").unwrap();
+    Cupey::Cupey::new_from(["cupey", "add", "mylib", library_dir.to_str().unwrap()].iter()).unwrap().run().unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "use", "mylib/fancy_button"].iter()).unwrap();
+    cupey_inst.current_dir = library_to_dir.clone();
+    cupey_inst.run().unwrap();
+    let copied_button = library_to_dir.join("button.dart").is_file();
+    let copied_dependency = library_to_dir.join("colors.dart").is_file();
+    common::clean_up(&library_dir);
+    common::clean_up(&library_to_dir);
+
+    let entry = Cupey::registry::Registry::load().unwrap().get("registry_fixture").unwrap().clone();
+    let from_dir_path = from_dir.canonicalize().unwrap();
+    let to_dir_count = common::folder_count(&to_dir);
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(from_dir_path, entry.path);
+    assert_eq!(Some("Test fixture".to_owned()), entry.description);
+    assert_eq!(3, to_dir_count);
+    assert!(missing_result.is_err());
+    assert!(copied_button);
+    assert!(copied_dependency);
+}
+
+
+#[test]
+fn cupey_dart_imports_work() {
+    let library_dir = common::fresh_dir("from_dir_dart");
+    fs::create_dir_all(library_dir.join("lib").join("buttons")).unwrap();
+    fs::create_dir_all(library_dir.join("lib").join("theme")).unwrap();
+    fs::write(library_dir.join("pubspec.yaml"), "name: widget_lib\n").unwrap();
+    fs::write(library_dir.join("lib").join("theme").join("colors.dart"), "const int primary = 1;\n").unwrap();
+    fs::write(library_dir.join("lib").join("buttons").join("fancy_button.dart"), concat!(
+        "import 'package:flutter/material.dart';\n",
+        "import 'package:widget_lib/theme/colors.dart';\n",
+        "export \"../theme/colors.dart\";\n",
+    )).unwrap();
+    let project_dir = common::fresh_dir("to_dir_dart");
+    fs::create_dir_all(project_dir.join("lib").join("widgets")).unwrap();
+    fs::write(project_dir.join("pubspec.yaml"), "name: my_app\n").unwrap();
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", library_dir.join("lib").to_str().unwrap(), "--dart-imports"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = project_dir.join("lib").join("widgets");
+    cupey_inst.copy_files().unwrap();
+
+    let button_string = fs::read_to_string(
+        project_dir.join("lib").join("widgets").join("buttons").join("fancy_button.dart")
+    ).unwrap();
+
+    // Archive and repository sources have nothing on disk to work imports out from
+    let (tar_gz_path, zip_path) = common::fixture_archives("from_dir_dart_archive");
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", tar_gz_path.to_str().unwrap(), "--dart-imports"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = project_dir.clone();
+    let archive_result = cupey_inst.copy_files();
+    let git_result = Cupey::Cupey::new_from([
+        "cupey", "--from-git", library_dir.to_str().unwrap(), "--dart-imports"
+    ].iter());
+    // Moving takes the library's pubspec.yaml away from under the imports
+    let move_result = Cupey::Cupey::new_from([
+        "cupey", "--from", library_dir.to_str().unwrap(), "--move", "--dart-imports"
+    ].iter());
+    common::clean_up(&library_dir);
+    common::clean_up(&project_dir);
+    common::clean_up(&tar_gz_path);
+    common::clean_up(&zip_path);
+
+    assert_eq!(concat!(
+        "import 'package:flutter/material.dart';\n",
+        "import 'package:my_app/widgets/theme/colors.dart';\n",
+        "export \"../theme/colors.dart\";\n",
+    ), button_string);
+    assert!(archive_result.is_err());
+    assert!(git_result.is_err());
+    assert!(move_result.is_err());
+}
+
+
+#[test]
+fn cupey_lock_prunes_bases() {
+    let from_dir = common::fresh_dir("from_dir_prune_bases");
+    let to_dir = common::fresh_dir("to_dir_prune_bases");
+    let text = "Only copied by the base pruning test";
+    fs::write(from_dir.join("kept.txt"), text).unwrap();
+    let binary = b"\x00\x01 only copied by the base pruning test";
+    fs::write(from_dir.join("image.bin"), binary).unwrap();
+    let bases_dir = common::use_test_cupey_home().join("bases");
+    let base_path = |contents: &[u8]| {
+        let hash = Cupey::sync::hash_bytes(contents);
+        bases_dir.join(&hash[..2]).join(&hash[2..])
+    };
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let text_kept = base_path(text.as_bytes()).is_file();
+    let binary_kept = base_path(binary).exists();
+
+    // Once no lock refers to it, an old enough base goes
+    fs::remove_file(from_dir.join("kept.txt")).unwrap();
+    fs::remove_file(to_dir.join("kept.txt")).unwrap();
+    let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60);
+    fs::File::options().write(true).open(base_path(text.as_bytes())).unwrap().set_modified(two_hours_ago).unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let text_pruned = !base_path(text.as_bytes()).exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(text_kept);
+    assert!(!binary_kept);
+    assert!(text_pruned);
+}
+
+
+#[test]
+fn cupey_lock_works() {
+    let from_dir = common::fixture_dir("from_dir_lock");
+    let to_dir = common::fresh_dir("to_dir_lock");
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    // Entries of deleted files are dropped on the next copy
+    fs::remove_file(from_dir.join("b.txt")).unwrap();
+    fs::remove_file(to_dir.join("b.txt")).unwrap();
+    fs::write(from_dir.join("d.txt"), "Added later").unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let no_lock_dir = common::fresh_dir("to_dir_no_lock");
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--no-lock"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = no_lock_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    let lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    let from_dir_path = from_dir.canonicalize().unwrap();
+    let no_lock_written = no_lock_dir.join(Cupey::lock::LOCK_FILE_NAME).exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+    common::clean_up(&no_lock_dir);
+
+    let paths: Vec<&str> = lock.entries.keys().map(String::as_str).collect();
+    assert_eq!(vec!["a.txt", "d.txt", "inner/c.txt"], paths);
+    let entry = &lock.entries["inner/c.txt"];
+    assert_eq!(from_dir_path.join("inner").join("c.txt"), entry.source);
+    assert_eq!(entry.source_hash, entry.hash);
+    assert!(entry.copied_at.ends_with('Z'));
+    assert!(!no_lock_written);
+}
+
+
+#[test]
+fn cupey_status_works() {
+    let from_dir = common::fixture_dir("from_dir_status");
+    let to_dir = common::fresh_dir("to_dir_status");
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    fs::write(from_dir.join("a.txt"), "Changed upstream").unwrap();
+    fs::write(to_dir.join("b.txt"), "Edited here").unwrap();
+    fs::write(from_dir.join("inner").join("c.txt"), "Changed upstream").unwrap();
+    fs::write(to_dir.join("inner").join("c.txt"), "Edited here").unwrap();
+
+    let lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    let statuses = Cupey::status::status(&lock).unwrap();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    let drifts: Vec<(&str, Cupey::status::Drift)> = statuses.iter()
+        .map(|file_status| (file_status.entry.path.as_str(), file_status.drift))
+        .collect();
+    assert_eq!(vec![
+        ("a.txt", Cupey::status::Drift::UpstreamChanged),
+        ("b.txt", Cupey::status::Drift::LocallyEdited),
+        ("inner/c.txt", Cupey::status::Drift::BothChanged),
+    ], drifts);
+}
+
+
+#[test]
+fn cupey_update_works() {
+    let from_dir = common::fresh_dir("from_dir_update");
+    fs::write(from_dir.join("merged.txt"), "one\ntwo\nthree\nfour\n").unwrap();
+    fs::write(from_dir.join("conflict.txt"), "one\ntwo\n").unwrap();
+    fs::write(from_dir.join("rewritten.txt"), "one\ntwo\n").unwrap();
+    let to_dir = common::fresh_dir("to_dir_update");
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    // Changed by a hook or an import rewrite during the copy
+    fs::write(to_dir.join("rewritten.txt"), "one\ntwo\n// formatted\n").unwrap();
+    let mut lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    lock.rehash(&[to_dir.join("rewritten.txt")]).unwrap();
+    lock.save().unwrap();
+    fs::write(from_dir.join("rewritten.txt"), "zero\none\ntwo\n").unwrap();
+
+    fs::write(to_dir.join("merged.txt"), "one local\ntwo\nthree\nfour\n").unwrap();
+    fs::write(from_dir.join("merged.txt"), "one\ntwo\nthree\nfour upstream\n").unwrap();
+    fs::write(to_dir.join("conflict.txt"), "one\ntwo local\n").unwrap();
+    fs::write(from_dir.join("conflict.txt"), "one\ntwo upstream\n").unwrap();
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "update", "--skip-conflicts"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.run().unwrap();
+    let merged_string = common::read_to_string(&to_dir.join("merged.txt"));
+    let skipped_string = common::read_to_string(&to_dir.join("conflict.txt"));
+    let rewritten_string = common::read_to_string(&to_dir.join("rewritten.txt"));
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "update", "conflict.txt"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.run().unwrap();
+    let conflict_string = common::read_to_string(&to_dir.join("conflict.txt"));
+
+    // Stays conflicted, and out of further updates, until the markers are gone
+    let drift_of_conflict = |to_dir: &std::path::Path| {
+        let lock = Cupey::lock::LockFile::load(to_dir).unwrap();
+        let statuses = Cupey::status::status(&lock).unwrap();
+        statuses.into_iter().find(|status| status.entry.path == "conflict.txt").unwrap().drift
+    };
+    let conflicted_drift = drift_of_conflict(&to_dir);
+    let mut lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    let outcomes = Cupey::update::update(&mut lock, &["conflict.txt".to_owned()], true).unwrap();
+    let conflict_kept = common::read_to_string(&to_dir.join("conflict.txt")) == conflict_string;
+    fs::write(to_dir.join("conflict.txt"), "one\ntwo local and upstream\n").unwrap();
+    let resolved_drift = drift_of_conflict(&to_dir);
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!("one local\ntwo\nthree\nfour upstream\n", merged_string);
+    assert_eq!("one\ntwo local\n", skipped_string);
+    assert_eq!("zero\none\ntwo\n// formatted\n", rewritten_string);
+    assert!(conflict_string.contains("<<<<<<< ours\ntwo local\n"));
+    assert!(conflict_string.contains("=======\ntwo upstream\n>>>>>>> theirs\n"));
+    assert_eq!(Cupey::status::Drift::Conflicted, conflicted_drift);
+    assert!(matches!(outcomes[0].1, Cupey::update::UpdateOutcome::Skipped(_)));
+    assert!(conflict_kept);
+    assert_eq!(Cupey::status::Drift::LocallyEdited, resolved_drift);
+}
+
+
+#[test]
+fn cupey_update_renders_templates() {
+    let from_dir = common::fresh_dir("from_dir_update_template");
+    fs::write(from_dir.join("{{name}}.txt"), "hello {{name}}\nmiddle\nline two\n").unwrap();
+    fs::write(from_dir.join("plain.txt"), "{{name}} one\n").unwrap();
+    let to_dir = common::fresh_dir("to_dir_update_template");
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--var", "name=widget"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    fs::write(to_dir.join("widget.txt"), "hello widget, edited\nmiddle\nline two\n").unwrap();
+    fs::write(from_dir.join("{{name}}.txt"), "hello {{name}}\nmiddle\nline two {{name | upper_case}}\n").unwrap();
+    fs::write(from_dir.join("plain.txt"), "{{name}} two\n").unwrap();
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "update"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.run().unwrap();
+    let merged_string = common::read_to_string(&to_dir.join("widget.txt"));
+    let updated_string = common::read_to_string(&to_dir.join("plain.txt"));
+    let lock = Cupey::lock::LockFile::load(&to_dir).unwrap();
+    let statuses = Cupey::status::status(&lock).unwrap();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!("hello widget, edited\nmiddle\nline two WIDGET\n", merged_string);
+    assert_eq!("widget two\n", updated_string);
+    let drifts: Vec<Cupey::status::Drift> = statuses.iter().map(|status| status.drift).collect();
+    assert_eq!(vec![Cupey::status::Drift::Unchanged, Cupey::status::Drift::LocallyEdited], drifts);
+}
+
+
+#[test]
+fn cupey_diff_works() {
+    let from_dir = common::fixture_dir("from_dir_diff");
+    let to_dir = common::fresh_dir("to_dir_diff");
+    fs::create_dir_all(to_dir.join("inner")).unwrap();
+    fs::write(to_dir.join("a.txt"), "Some stuff in a").unwrap();
+    fs::write(to_dir.join("b.txt"), "Changed stuff in b").unwrap();
+    fs::write(to_dir.join("inner").join("d.txt"), "Only in the destination").unwrap();
+
+    let diffs = Cupey::diff::compare(&from_dir, &to_dir, &mut Cupey::CopyJob::default()).unwrap();
+    let b_diff = diffs[1].unified_diff(false).unwrap().unwrap();
+    let stat = Cupey::diff::stat(&diffs, false).unwrap();
+
+    // Templated sources compare by what a copy writes, the rest of the project isn't listed
+    let template_dir = common::fresh_dir("from_dir_diff_template");
+    fs::write(template_dir.join(".cupey.toml"), "[variables.name]\ndefault = \"widget\"\n").unwrap();
+    fs::create_dir_all(template_dir.join("inner")).unwrap();
+    fs::write(template_dir.join("{{name}}.txt"), "hello {{name}}\n").unwrap();
+    fs::write(template_dir.join("inner").join("{{name}}.txt"), "bye {{name}}\n").unwrap();
+    let project_dir = common::fresh_dir("to_dir_diff_template");
+    fs::create_dir_all(project_dir.join("inner")).unwrap();
+    fs::create_dir_all(project_dir.join("lib")).unwrap();
+    fs::write(project_dir.join("widget.txt"), "hello widget\n").unwrap();
+    fs::write(project_dir.join("inner").join("widget.txt"), "bye widget, edited\n").unwrap();
+    fs::write(project_dir.join("inner").join("extra.txt"), "Only in the project").unwrap();
+    fs::write(project_dir.join("pubspec.yaml"), "name: my_app\n").unwrap();
+    fs::write(project_dir.join("lib").join("main.dart"), "void main() {}\n").unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "diff", "--from", template_dir.to_str().unwrap(), "--no-input"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = project_dir.clone();
+    cupey_inst.run().unwrap();
+    let vars = std::iter::once(("name".to_owned(), "widget".to_owned())).collect();
+    let mut job = Cupey::CopyJob { template: Some(Cupey::template::Template::new(vars)), ..Default::default() };
+    let template_diffs = Cupey::diff::compare(&template_dir, &project_dir, &mut job).unwrap();
+    let inner_diff = template_diffs[1].unified_diff(false).unwrap().unwrap();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+    common::clean_up(&template_dir);
+    common::clean_up(&project_dir);
+
+    let changes: Vec<(String, Cupey::diff::Change)> = diffs.iter()
+        .map(|diff| (diff.relative_path.to_str().unwrap().replace('\\', "/"), diff.change))
+        .collect();
+    assert_eq!(vec![
+        ("a.txt".to_owned(), Cupey::diff::Change::Identical),
+        ("b.txt".to_owned(), Cupey::diff::Change::Changed),
+        ("inner/c.txt".to_owned(), Cupey::diff::Change::Added),
+        ("inner/d.txt".to_owned(), Cupey::diff::Change::DestinationOnly),
+    ], changes);
+    assert!(b_diff.starts_with("--- a/b.txt\n+++ b/b.txt\n"));
+    assert!(b_diff.contains("-Changed stuff in b\n"));
+    assert!(stat.starts_with(" b.txt |    2 +-\n"));
+    let template_changes: Vec<(String, Cupey::diff::Change)> = template_diffs.iter()
+        .map(|diff| (diff.relative_path.to_str().unwrap().replace('\\', "/"), diff.change))
+        .collect();
+    assert_eq!(vec![
+        ("inner/extra.txt".to_owned(), Cupey::diff::Change::DestinationOnly),
+        ("inner/widget.txt".to_owned(), Cupey::diff::Change::Changed),
+        ("widget.txt".to_owned(), Cupey::diff::Change::Identical),
+    ], template_changes);
+    assert!(inner_diff.contains("-bye widget, edited\n+bye widget\n"));
+}
+
+
+#[test]
+fn cupey_config_works() {
+    let from_dir = common::fixture_dir("from_dir_config");
+    let to_dir = common::fresh_dir("to_dir_config");
+    fs::write(to_dir.join(".cupey.toml"), r#"
+        [defaults]
+        exclude = ["inner/**"]
+
+        [profiles.refresh]
+        overwrite = true
+    "#).unwrap();
+    common::create_txt_file("Old stuff in a", &to_dir.join("a.txt"), None);
+    let from_arg = from_dir.to_str().unwrap();
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_arg].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let kept_string = common::read_to_string(&to_dir.join("a.txt"));
+    let inner_excluded = !to_dir.join("inner").join("c.txt").exists();
+
+    // The command line turns settings off as well as on
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_arg, "--profile", "refresh", "--no-overwrite"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let not_overwritten_string = common::read_to_string(&to_dir.join("a.txt"));
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_arg, "--profile", "refresh"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let overwritten_string = common::read_to_string(&to_dir.join("a.txt"));
+
+    // Archive sources and diffs leave out the same files
+    let (tar_gz_path, zip_path) = common::fixture_archives("from_dir_config_archive");
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", tar_gz_path.to_str().unwrap()].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let archive_inner_excluded = !to_dir.join("inner").join("c.txt").exists();
+    let mut excludes = globset::GlobSetBuilder::new();
+    excludes.add(globset::Glob::new("inner/**").unwrap());
+    let mut job = Cupey::CopyJob { exclude: Some(excludes.build().unwrap()), ..Default::default() };
+    let diffs = Cupey::diff::compare(&from_dir, &to_dir, &mut job).unwrap();
+    let diff_inner_excluded = diffs.iter().all(|diff| !diff.relative_path.starts_with("inner"));
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_arg, "--profile", "missing"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let missing_result = cupey_inst.copy_files();
+
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_arg, "--no-config"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let inner_copied = to_dir.join("inner").join("c.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+    common::clean_up(&tar_gz_path);
+    common::clean_up(&zip_path);
+
+    assert_eq!("Old stuff in a", kept_string);
+    assert_eq!("Old stuff in a", not_overwritten_string);
+    assert!(inner_excluded);
+    assert!(archive_inner_excluded);
+    assert!(diff_inner_excluded);
+    assert_eq!("Some stuff in a", overwritten_string);
+    assert!(missing_result.is_err());
+    assert!(inner_copied);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn cupey_hooks_work() {
+    let from_dir = common::fixture_dir("from_dir_hooks");
+    fs::write(from_dir.join(".cupey.toml"), r#"
+        [hooks]
+        post = ["sort > copied.out"]
+    "#).unwrap();
+    let to_dir = common::fresh_dir("to_dir_hooks");
+
+    // A copied folder's hooks need the user's go ahead
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap(), "--no-input"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let unconfirmed_skipped = !to_dir.join("copied.out").exists();
+    let mut cupey_inst = Cupey::Cupey::new_from(["cupey", "--from", from_dir.to_str().unwrap(), "--yes", "--no-hooks"].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let no_hooks_skipped = !to_dir.join("copied.out").exists();
+
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--yes", "--overwrite"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let copied_string = common::read_to_string(&to_dir.join("copied.out"));
+
+    // A failing hook puts back what the run replaced and removes what it added
+    fs::write(from_dir.join(".cupey.toml"), r#"
+        [hooks]
+        per_file = ["test \"$CUPEY_PATH\" != \"$CUPEY_DESTINATION/b.txt\""]
+    "#).unwrap();
+    fs::write(from_dir.join("d.txt"), "New stuff in d").unwrap();
+    fs::write(to_dir.join("a.txt"), "Edited stuff in a").unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from([
+        "cupey", "--from", from_dir.to_str().unwrap(), "--overwrite", "--rollback", "--yes"
+    ].iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let hook_result = cupey_inst.copy_files();
+
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    let d_removed = !to_dir.join("d.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    let expected: Vec<String> = ["a.txt", "b.txt", "inner/c.txt"].iter()
+        .map(|name| format!("{}\n", to_dir.join(name).display()))
+        .collect();
+    assert!(unconfirmed_skipped);
+    assert!(no_hooks_skipped);
+    assert_eq!(expected.concat(), copied_string);
+    assert!(hook_result.is_err());
+    assert_eq!("Edited stuff in a", a_string);
+    assert!(d_removed);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn cupey_rollback_keeps_sync_state() {
+    let from_dir = common::fixture_dir("from_dir_rollback_sync");
+    let to_dir = common::fresh_dir("to_dir_rollback_sync");
+    let from_arg = from_dir.to_str().unwrap();
+    let incremental_args = ["cupey", "--from", from_arg, "--incremental", "--rollback", "--yes"];
+
+    let mut cupey_inst = Cupey::Cupey::new_from(incremental_args.iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+
+    // The rolled back run leaves the state as the first run saved it
+    fs::write(from_dir.join("a.txt"), "Changed stuff in a").unwrap();
+    fs::write(from_dir.join(".cupey.toml"), "[hooks]\npost = [\"false\"]\n").unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from(incremental_args.iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    let hook_result = cupey_inst.copy_files();
+    let rolled_back_string = common::read_to_string(&to_dir.join("a.txt"));
+
+    fs::remove_file(from_dir.join(".cupey.toml")).unwrap();
+    let mut cupey_inst = Cupey::Cupey::new_from(incremental_args.iter()).unwrap();
+    cupey_inst.current_dir = to_dir.clone();
+    cupey_inst.copy_files().unwrap();
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert!(hook_result.is_err());
+    assert_eq!("Some stuff in a", rolled_back_string);
+    assert_eq!("Changed stuff in a", a_string);
+}
@@ -1,5 +1,3 @@
-use Cupey;
-use std::borrow::Borrow;
 use std::env;
 use std::fs;
 use std::path;
@@ -8,8 +6,6 @@ use std::panic;
 
 mod common;
 
-type GeneralErrors = Result<(), Box<dyn std::error::Error>>;
-
 
 #[test]
 fn visit_dirs_works() {
@@ -66,9 +62,9 @@ fn copier_overwrite_works() -> Result<(), Box<dyn std::error::Error>> {
     common::create_txt_file("shell", &dest_file_path, None);
 
     // copy newly created file to cupey test folder, which has a similar file with different content
-    Cupey::copier(&mut dest_file_path, &mut cupey_test_folder, true)?;
+    Cupey::copier(&dest_file_path, &mut cupey_test_folder, true)?;
 
-    let string_to_read = common::read_to_string(&mut dest_file_path);
+    let string_to_read = common::read_to_string(&dest_file_path);
 
     assert_eq!(String::from("shell"), string_to_read);
 
@@ -93,7 +89,7 @@ fn test_copier_overwrite_works () {
 
 
     let clean_up_closure =  |paths: Vec<&path::Path>| {
-        let _: () = paths.into_iter().map(|path| common::clean_up(path)).collect();
+        let _: () = paths.into_iter().map(common::clean_up).collect();
     };
 
     match panic::catch_unwind(|| { copier_overwrite_works() }) {
@@ -119,3 +115,420 @@ fn test_copier_overwrite_works () {
 
 
 
+
+#[test]
+fn mirror_finds_extraneous_paths() {
+    let from_dir = common::fixture_dir("from_dir_mirror_unit");
+    let to_dir = common::fresh_dir("to_dir_mirror_unit");
+
+    Cupey::visit_dirs(from_dir.as_path(), &to_dir, false).unwrap();
+    common::create_txt_file("stale", &to_dir.join("stale.txt"), None);
+    common::create_txt_file("stale", &to_dir.join("inner").join("stale.txt"), None);
+    fs::create_dir_all(to_dir.join("stale_folder").join("nested")).unwrap();
+    fs::create_dir_all(to_dir.join(".git")).unwrap();
+    fs::write(to_dir.join("inner").join(".cupey.toml"), "").unwrap();
+
+    let in_subfolders = Cupey::mirror::extraneous_paths(std::slice::from_ref(&from_dir), &to_dir, false).unwrap();
+    let extraneous = Cupey::mirror::extraneous_paths(std::slice::from_ref(&from_dir), &to_dir, true).unwrap();
+    Cupey::mirror::remove_paths(&extraneous, None).unwrap();
+    let from_dir_size = common::folder_size(&from_dir);
+    let to_dir_size = common::folder_size(&to_dir);
+    let to_dir_count = common::folder_count(&to_dir);
+
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(in_subfolders, vec![to_dir.join("inner").join("stale.txt")]);
+    assert_eq!(extraneous, vec![
+        to_dir.join("inner").join("stale.txt"),
+        to_dir.join("stale.txt"),
+        to_dir.join("stale_folder"),
+    ]);
+    assert_eq!(from_dir_size, to_dir_size);
+    assert_eq!(4, to_dir_count);
+}
+
+#[test]
+fn sync_state_detects_changes() {
+    let from_dir = common::fixture_dir("from_dir_sync_unit");
+    let to_dir = common::fresh_dir("to_dir_sync_unit");
+    let state_path = to_dir.join("state");
+    let source = from_dir.join("a.txt");
+    let dest = to_dir.join("a.txt");
+    let relative_path = path::Path::new("a.txt");
+
+    let mut state = Cupey::sync::SyncState::load_from(state_path.clone()).unwrap();
+    let first = state.check(relative_path, &source, &dest).unwrap();
+    fs::copy(&source, &dest).unwrap();
+    state.record(relative_path, &source, &dest).unwrap();
+    state.save().unwrap();
+
+    let mut state = Cupey::sync::SyncState::load_from(state_path).unwrap();
+    let unchanged = state.check(relative_path, &source, &dest).unwrap();
+    common::create_txt_file("edited", &dest, Some(true));
+    let edited = state.check(relative_path, &source, &dest).unwrap();
+
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!(Cupey::sync::SyncAction::Copy, first);
+    assert_eq!(Cupey::sync::SyncAction::UpToDate, unchanged);
+    assert_eq!(Cupey::sync::SyncAction::LocallyModified, edited);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watcher_syncs_changes() {
+    use std::time::Duration;
+    use Cupey::watch::{SourceWatcher, WatchOptions};
+
+    let from_dir = common::fixture_dir("from_dir_watch_unit");
+    let to_dir = common::fresh_dir("to_dir_watch_unit");
+    let options = WatchOptions { mirror: true, debounce: Duration::from_millis(50) };
+    let mut watcher = SourceWatcher::new(&from_dir, &to_dir, options).unwrap();
+    Cupey::visit_dirs(&from_dir, &to_dir, false).unwrap();
+
+    // Edit a file, add a folder with a file in it and delete a file
+    common::create_txt_file("Edited a", &from_dir.join("a.txt"), Some(true));
+    fs::create_dir_all(from_dir.join("new_folder")).unwrap();
+    common::create_txt_file("New d", &from_dir.join("new_folder").join("d.txt"), None);
+    fs::remove_file(from_dir.join("b.txt")).unwrap();
+    // A folder is in the way of this one, the rest is still copied
+    fs::create_dir_all(to_dir.join("blocked.txt")).unwrap();
+    common::create_txt_file("Blocked e", &from_dir.join("blocked.txt"), None);
+    watcher.sync_changes(Some(Duration::from_secs(5))).unwrap();
+
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    let d_string = common::read_to_string(&to_dir.join("new_folder").join("d.txt"));
+    let b_exists = to_dir.join("b.txt").exists();
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+
+    assert_eq!("Edited a", a_string);
+    assert_eq!("New d", d_string);
+    assert!(!b_exists);
+}
+
+#[test]
+fn relative_path_works() {
+    let relative = Cupey::link::relative_path(
+        path::Path::new("/home/user/project/lib"), path::Path::new("/home/user/widgets/button.dart")
+    );
+    assert_eq!(path::Path::new("../../widgets/button.dart"), relative);
+}
+
+#[test]
+fn template_render_works() {
+    let mut vars = std::collections::BTreeMap::new();
+    vars.insert("widget_name".to_owned(), "fancy_button".to_owned());
+    let template = Cupey::template::Template::new(vars);
+
+    assert_eq!("fancy_button.dart", template.render("{{widget_name}}.dart").unwrap());
+    assert_eq!("fancy_button", template.render("{{ widget_name }}").unwrap());
+    // Not placeholders, left alone
+    assert_eq!("${{ env.HOME }} {{", template.render("${{ env.HOME }} {{").unwrap());
+    assert_eq!(
+        Cupey::template::RenderError::UnknownVar("class_name".to_owned()),
+        template.render("{{class_name}}").unwrap_err()
+    );
+
+    let binary = vec![0u8, 123, 123, 120, 125, 125];
+    assert_eq!(binary, template.render_contents(binary.clone(), path::Path::new("image.png")).unwrap());
+}
+
+#[test]
+fn template_filters_work() {
+    let mut vars = std::collections::BTreeMap::new();
+    vars.insert("name".to_owned(), "fancyHTTPButton2".to_owned());
+    let template = Cupey::template::Template::new(vars);
+
+    assert_eq!("fancy_http_button2", template.render("{{name | snake_case}}").unwrap());
+    assert_eq!("FancyHttpButton2", template.render("{{name|pascal_case}}").unwrap());
+    assert_eq!("fancyHttpButton2", template.render("{{ name | camel_case }}").unwrap());
+    assert_eq!("fancy-http-button2", template.render("{{name | kebab_case}}").unwrap());
+    assert_eq!("FANCY_HTTP_BUTTON2", template.render("{{name | snake_case | upper_case}}").unwrap());
+    assert_eq!(
+        Cupey::template::RenderError::UnknownFilter("title_case".to_owned()),
+        template.render("{{name | title_case}}").unwrap_err()
+    );
+}
+
+#[test]
+fn manifest_resolves_vars() {
+    let manifest = Cupey::manifest::Manifest::parse(r#"
+        exclude = ["*.g.dart"]
+        verbatim = ["assets/**"]
+
+        [variables.name]
+        description = "Widget name"
+        pattern = "[a-z_]+"
+
+        [variables.author]
+        default = "nobody"
+    "#, path::Path::new(".cupey.toml")).unwrap();
+
+    assert!(manifest.excludes(path::Path::new(".cupey.toml")));
+    assert!(manifest.excludes(path::Path::new("lib/button.g.dart")));
+    assert!(!manifest.renders(path::Path::new("assets/logo.svg")));
+    assert!(manifest.renders(path::Path::new("lib/button.dart")));
+
+    let mut given = std::collections::BTreeMap::new();
+    // Nobody to ask and no default
+    assert!(manifest.resolve_vars(&given, None).is_err());
+
+    // Asked in name order: an empty answer takes author's default and an
+    // invalid name is asked again
+    let mut answers = std::io::Cursor::new("\nNot Valid\nfancy_button\n");
+    let vars = manifest.resolve_vars(&given, Some(&mut answers)).unwrap();
+    assert_eq!("fancy_button", vars["name"]);
+    assert_eq!("nobody", vars["author"]);
+
+    given.insert("name".to_owned(), "Not Valid".to_owned());
+    assert!(manifest.resolve_vars(&given, None).is_err());
+}
+
+#[test]
+fn search_ranks_hits() {
+    let from_dir = common::fixture_dir("from_dir_search");
+    fs::write(from_dir.join("stuff_notes.md"), "binary\0stuff").unwrap();
+    let mut registry = Cupey::registry::Registry::load_from(from_dir.join("registry.toml")).unwrap();
+    registry.add("fixture", &from_dir, Some("Some test fixture".to_owned())).unwrap();
+
+    let name_hits = Cupey::search::search(&registry, "stuff", false).unwrap();
+    let content_hits = Cupey::search::search(&registry, "nested STUFF", true).unwrap();
+    let source_hits = Cupey::search::search(&registry, "test fixture", false).unwrap();
+    let inner_path = registry.resolve("fixture/inner/c.txt").unwrap();
+    let escape_result = registry.resolve("fixture/../from_dir_search");
+    common::clean_up(&from_dir);
+
+    assert_eq!(vec!["fixture/stuff_notes.md"], name_hits.iter().map(|hit| hit.use_path()).collect::<Vec<_>>());
+    assert_eq!(1, content_hits.len());
+    assert_eq!("fixture/inner/c.txt", content_hits[0].use_path());
+    assert_eq!(
+        Cupey::search::HitKind::Contents { line_number: 1, line: "Nested stuff in c".to_owned() },
+        content_hits[0].kind
+    );
+    assert_eq!(Cupey::search::HitKind::Source, source_hits[0].kind);
+    assert!(inner_path.ends_with("inner/c.txt"));
+    assert!(escape_result.is_err());
+}
+
+#[test]
+fn registry_resolves_dependencies() {
+    let libraries_dir = common::fresh_dir("from_dir_dependencies");
+    let mut registry = Cupey::registry::Registry::load_from(libraries_dir.join("registry.toml")).unwrap();
+    for (name, dependencies) in &[
+        ("button", r#"["colors", "icons"]"#), ("icons", r#"["colors"]"#), ("colors", "[]"),
+        ("loop_a", r#"["loop_b"]"#), ("loop_b", r#"["loop_a"]"#), ("broken", r#"["missing"]"#),
+    ] {
+        let dir = libraries_dir.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".cupey.toml"), format!("dependencies = {}", dependencies)).unwrap();
+        registry.add(name, &dir, None).unwrap();
+    }
+
+    let button = registry.with_dependencies("button").unwrap();
+    let cycle = registry.with_dependencies("loop_a").unwrap_err().to_string();
+    let broken = registry.with_dependencies("broken").unwrap_err().to_string();
+    common::clean_up(&libraries_dir);
+
+    assert_eq!(vec!["colors", "icons", "button"], button);
+    assert!(cycle.contains("loop_a -> loop_b -> loop_a"));
+    assert!(broken.contains("'broken' depends on 'missing'"));
+}
+
+#[test]
+fn registry_resolves_dependencies_inside_a_library() {
+    let library_dir = common::fresh_dir("from_dir_library_dependencies");
+    let mut registry = Cupey::registry::Registry::load_from(library_dir.join("registry.toml")).unwrap();
+    for (entry, dependencies) in &[
+        ("fancy_button", r#"["theme", "icons/star.dart"]"#), ("theme", r#"["theme/colors.dart"]"#),
+        ("loop_a", r#"["loop_b"]"#), ("loop_b", r#"["loop_a"]"#),
+    ] {
+        let dir = library_dir.join("mylib").join(entry);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".cupey.toml"), format!("dependencies = {}", dependencies)).unwrap();
+    }
+    fs::write(library_dir.join("mylib").join("theme").join("colors.dart"), "").unwrap();
+    fs::create_dir_all(library_dir.join("icons")).unwrap();
+    fs::write(library_dir.join("icons").join("star.dart"), "").unwrap();
+    registry.add("mylib", &library_dir.join("mylib"), None).unwrap();
+    registry.add("icons", &library_dir.join("icons"), None).unwrap();
+
+    let fancy_button = registry.with_dependencies("mylib/fancy_button/").unwrap();
+    let cycle = registry.with_dependencies("mylib/loop_a").unwrap_err().to_string();
+    common::clean_up(&library_dir);
+
+    assert_eq!(vec!["mylib/theme/colors.dart", "mylib/theme", "icons/star.dart", "mylib/fancy_button"], fancy_button);
+    assert!(cycle.contains("mylib/loop_a -> mylib/loop_b -> mylib/loop_a"));
+}
+
+#[test]
+fn pubspec_merge_works() {
+    let fragment = Cupey::pubspec::PubspecRequirements::from_fragment(concat!(
+        "# Needed by fancy_button\n",
+        "dependencies:\n",
+        "  provider: ^6.0.0\n",
+        "  intl: '>=0.17.0 <0.19.0'\n",
+        "  http: ^1.0.0\n",
+        "dev_dependencies:\n",
+        "  mocktail: ^1.0.0\n",
+        "flutter:\n",
+        "  assets:\n",
+        "    - assets/fancy_button/\n",
+    ), path::Path::new("cupey.pubspec.yaml")).unwrap();
+
+    let outcome = fragment.merge_into(concat!(
+        "name: my_app\n",
+        "\n",
+        "dependencies:\n",
+        "  flutter:\n",
+        "    sdk: flutter\n",
+        "  # Pinned for the date picker\n",
+        "  intl: 0.18.1\n",
+        "  provider: ^5.0.0\n",
+        "\n",
+        "flutter:\n",
+        "  uses-material-design: true\n",
+    ));
+
+    assert_eq!(concat!(
+        "name: my_app\n",
+        "\n",
+        "dependencies:\n",
+        "  flutter:\n",
+        "    sdk: flutter\n",
+        "  # Pinned for the date picker\n",
+        "  intl: 0.18.1\n",
+        "  provider: ^5.0.0\n",
+        "  http: ^1.0.0\n",
+        "\n",
+        "flutter:\n",
+        "  uses-material-design: true\n",
+        "  assets:\n",
+        "    - assets/fancy_button/\n",
+        "\n",
+        "dev_dependencies:\n",
+        "  mocktail: ^1.0.0\n",
+    ), outcome.pubspec);
+    assert_eq!(1, outcome.conflicts.len());
+    assert_eq!("provider", outcome.conflicts[0].package);
+
+    // A "#" inside quotes is part of the value, not a comment
+    let fragment = Cupey::pubspec::PubspecRequirements::from_fragment(concat!(
+        "flutter:\n",
+        "  assets:\n",
+        "    - \"assets/icons #1/\"\n",
+        "    - \"assets/icons #2/\"\n",
+    ), path::Path::new("cupey.pubspec.yaml")).unwrap();
+    let outcome = fragment.merge_into(concat!(
+        "name: my_app\n",
+        "flutter:\n",
+        "  assets:\n",
+        "    - \"assets/icons #1/\" # App icons\n",
+        "    - 'assets/it''s #3/'\n",
+    ));
+    assert_eq!(concat!(
+        "name: my_app\n",
+        "flutter:\n",
+        "  assets:\n",
+        "    - \"assets/icons #1/\" # App icons\n",
+        "    - 'assets/it''s #3/'\n",
+        "    - 'assets/icons #2/'\n",
+    ), outcome.pubspec);
+    assert!(Cupey::pubspec::compatible("^1.2.0", ">=1.0.0 <2.0.0"));
+    assert!(!Cupey::pubspec::compatible("1.0.0", "^1.1.0"));
+}
+
+#[test]
+fn transforms_run_in_order() {
+    use Cupey::transform::{LicenseHeader, LineEndings, Transform};
+
+    // Shouts, except at files it is told to refuse
+    #[derive(Debug)]
+    struct Uppercase {
+        refuse: &'static str,
+    }
+
+    impl Transform for Uppercase {
+        fn name(&self) -> &str {
+            "uppercase"
+        }
+
+        fn transform(
+            &self, relative_path: &path::Path, input: &mut dyn std::io::Read
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            if relative_path.ends_with(self.refuse) {
+                return Err("refused".into())
+            }
+            let mut contents = Vec::new();
+            input.read_to_end(&mut contents)?;
+            Ok(contents.to_ascii_uppercase())
+        }
+    }
+
+    let from_dir = common::fixture_dir("from_dir_transform_unit");
+    common::create_txt_file("one\r\ntwo\r\n", &from_dir.join("a.txt"), Some(true));
+    let to_dir = common::fresh_dir("to_dir_transform_unit");
+    let failed_dir = common::fresh_dir("to_dir_transform_failed_unit");
+
+    let mut job = Cupey::CopyJob::new(false);
+    job.add_transform(LineEndings::Lf);
+    job.add_transform(Uppercase { refuse: "none" });
+    job.add_transform(LicenseHeader { header: "// MIT".to_owned(), extensions: vec!["txt".to_owned()] });
+    Cupey::visit_dirs_with(&from_dir, &to_dir, &mut job).unwrap();
+    let a_string = common::read_to_string(&to_dir.join("a.txt"));
+    let c_string = common::read_to_string(&to_dir.join("inner").join("c.txt"));
+
+    let mut failing_job = Cupey::CopyJob::new(false);
+    failing_job.add_transform(Uppercase { refuse: "c.txt" });
+    let failed = Cupey::visit_dirs_with(&from_dir, &failed_dir, &mut failing_job).unwrap_err();
+    let c_exists = failed_dir.join("inner").join("c.txt").exists();
+
+    // Moved files would lose their originals without being transformed
+    let mut moving_job = Cupey::CopyJob::new(false);
+    moving_job.move_files = true;
+    moving_job.add_transform(LineEndings::Lf);
+    let moving = Cupey::visit_dirs_with(&from_dir, &failed_dir, &mut moving_job).unwrap_err();
+    let a_kept = from_dir.join("a.txt").exists();
+
+    // Transforms get the path the file lands at, placeholders filled in
+    fs::create_dir_all(from_dir.join("{{name}}")).unwrap();
+    fs::write(from_dir.join("{{name}}").join("d.txt"), "Templated stuff in d").unwrap();
+    let templated_dir = common::fresh_dir("to_dir_transform_templated_unit");
+    let mut templated_job = Cupey::CopyJob::new(false);
+    let vars = std::iter::once(("name".to_owned(), "widget".to_owned())).collect();
+    templated_job.template = Some(Cupey::template::Template::new(vars));
+    templated_job.add_transform(Uppercase { refuse: "widget/d.txt" });
+    let templated = Cupey::visit_dirs_with(&from_dir, &templated_dir, &mut templated_job).unwrap_err();
+
+    // Incremental runs pick up a changed transform even though the source didn't change
+    let sync_from_dir = common::fresh_dir("from_dir_transform_sync_unit");
+    fs::write(sync_from_dir.join("e.txt"), "one\ntwo\n").unwrap();
+    let sync_to_dir = common::fresh_dir("to_dir_transform_sync_unit");
+    let state_path = sync_to_dir.with_extension("state");
+    for line_endings in [LineEndings::Lf, LineEndings::Crlf] {
+        let mut sync_job = Cupey::CopyJob::new(false);
+        sync_job.sync_state = Some(Cupey::sync::SyncState::load_from(state_path.clone()).unwrap());
+        sync_job.add_transform(line_endings);
+        Cupey::visit_dirs_with(&sync_from_dir, &sync_to_dir, &mut sync_job).unwrap();
+        sync_job.sync_state.unwrap().save().unwrap();
+    }
+    let e_string = common::read_to_string(&sync_to_dir.join("e.txt"));
+    common::clean_up(&from_dir);
+    common::clean_up(&to_dir);
+    common::clean_up(&failed_dir);
+    common::clean_up(&templated_dir);
+    common::clean_up(&sync_from_dir);
+    common::clean_up(&sync_to_dir);
+    common::clean_up(&state_path);
+
+    assert_eq!("// MIT\nONE\nTWO\n", a_string);
+    assert_eq!("// MIT\nNESTED STUFF IN C", c_string);
+    assert!(failed.to_string().contains("Transform 'uppercase' failed on 'inner/c.txt': refused"));
+    assert!(!c_exists);
+    assert!(moving.to_string().contains("files that are moved or linked are left as they are"));
+    assert!(a_kept);
+    assert!(templated.to_string().contains("Transform 'uppercase' failed on 'widget/d.txt': refused"));
+    assert_eq!("one\r\ntwo\r\n", e_string);
+}